@@ -3,6 +3,7 @@ use once_cell::sync::OnceCell;
 use serde::Deserialize;
 use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 use tracing::{debug, error, info};
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
@@ -28,12 +29,149 @@ lazy_static! {
             }
         }
     };
+    static ref DEMO_MODE: bool = env::args().any(|arg| arg == "--demo");
+    static ref BAKE_MACAROON_PATH: Option<String> = {
+        let args: Vec<String> = env::args().collect();
+        args.iter()
+            .position(|s| s == "--bake-macaroon")
+            .and_then(|pos| args.get(pos + 1).cloned())
+    };
+}
+
+/// `--demo` runs against the in-memory mock node regardless of the configured backend.
+pub fn is_demo_mode() -> bool {
+    *DEMO_MODE
+}
+
+/// `--bake-macaroon <path>` writes a macaroon limited to invoices to `path` and exits.
+pub fn get_bake_macaroon_path() -> Option<&'static str> {
+    BAKE_MACAROON_PATH.as_deref()
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct User {
     pub username: String,
     pub pubkey: String,
+    /// Name of a `[[backends]]` entry to receive this user's payments.
+    pub backend: Option<String>,
+    /// Overrides of the global invoice settings of the same names.
+    pub min_sendable_msat: Option<i64>,
+    pub max_sendable_msat: Option<i64>,
+    pub comment_allowed: Option<usize>,
+    pub invoice_expiry_secs: Option<i64>,
+    pub blinded_paths: Option<BlindedPaths>,
+    /// LUD-18 details to ask payers for.
+    pub payer_data: Option<PayerData>,
+    pub success_action: Option<SuccessAction>,
+    /// LUD-06 `text/long-desc` shown by wallets on the payment screen.
+    pub long_description: Option<String>,
+    /// PNG or JPEG shown next to the address, scaled down at startup when large.
+    pub avatar_path: Option<String>,
+}
+
+/// What the payer's wallet shows once paid (LUD-09, LUD-10). Text may use the placeholders
+/// `{username}`, `{amount_msat}`, `{amount_sat}`, `{payment_hash}` and `{comment}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: String,
+    },
+    /// `plaintext` is encrypted with the payment preimage, so only the payer can read it.
+    Aes {
+        description: String,
+        plaintext: String,
+    },
+}
+
+/// A fiat currency payers can choose amounts in.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Currency {
+    /// ISO 4217 code, e.g. `EUR`.
+    pub code: String,
+    pub name: String,
+    pub symbol: String,
+    /// Digits after the decimal point; amounts are sent in the smallest unit.
+    pub decimals: Option<u32>,
+    /// Prices older than this are not converted with.
+    pub max_price_age_secs: Option<u64>,
+    pub price_source: PriceSourceConfig,
+}
+
+/// Where the price of one bitcoin in a currency's main unit comes from.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PriceSourceConfig {
+    /// A fixed price, which never goes stale.
+    Static { price: f64 },
+    /// A JSON document fetched over HTTP, with the price at `pointer` (RFC 6901).
+    Http {
+        url: String,
+        pointer: String,
+        refresh_secs: Option<u64>,
+    },
+    /// A bare number or JSON document kept up to date by another program. The file's
+    /// modification time is taken as when the price was observed.
+    File {
+        path: String,
+        pointer: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayerDataRequirement {
+    Optional,
+    Mandatory,
+}
+
+/// LUD-18 payer fields and whether each must be given. Fields left unset are not asked for.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PayerData {
+    pub name: Option<PayerDataRequirement>,
+    pub pubkey: Option<PayerDataRequirement>,
+    pub identifier: Option<PayerDataRequirement>,
+    pub email: Option<PayerDataRequirement>,
+    /// An LNURL-auth signature of a challenge handed out with the pay request.
+    pub auth: Option<PayerDataRequirement>,
+}
+
+impl PayerData {
+    /// The requested fields, by their LUD-18 names.
+    pub fn fields(&self) -> [(&'static str, Option<PayerDataRequirement>); 5] {
+        [
+            ("name", self.name),
+            ("pubkey", self.pubkey),
+            ("identifier", self.identifier),
+            ("email", self.email),
+            ("auth", self.auth),
+        ]
+    }
+}
+
+/// Blinded paths to add to invoices instead of route hints, on LND. Unset counts are left to
+/// LND's own defaults.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BlindedPaths {
+    pub enabled: bool,
+    pub num_paths: Option<u32>,
+    pub num_hops: Option<u32>,
+    pub min_real_hops: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    #[default]
+    Lnd,
+    LndRest,
+    Cln,
+    Nwc,
+    Mock,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -43,6 +181,38 @@ pub struct Lnd {
     pub macaroon_path: Option<String>,
     pub macaroon_hex: Option<String>,
     pub socket: String,
+    pub rest_socket: Option<String>,
+}
+
+/// One of several LND nodes to fail over between; lower `priority` is preferred.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LndNode {
+    pub name: String,
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(flatten)]
+    pub lnd: Lnd,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Cln {
+    pub socket_path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Nwc {
+    pub connection_string: String,
+}
+
+/// A node with its own credentials, referenced by name from `User::backend`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NamedBackend {
+    pub name: String,
+    #[serde(default)]
+    pub kind: BackendKind,
+    pub lnd: Option<Lnd>,
+    pub cln: Option<Cln>,
+    pub nwc: Option<Nwc>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -60,10 +230,40 @@ pub struct Nostr {
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub domain: String,
+    pub min_sendable_msat: Option<i64>,
     pub max_sendable_msat: Option<i64>,
+    pub comment_allowed: Option<usize>,
+    pub invoice_expiry_secs: Option<i64>,
     pub include_hop_hints: Option<bool>,
+    /// Success action for users without their own.
+    pub success_action: Option<SuccessAction>,
+    /// Fiat currencies advertised with every pay request.
+    pub currencies: Option<Vec<Currency>>,
+    /// Inbound capacity held back when advertising `maxSendable`.
+    pub inbound_safety_margin_msat: Option<i64>,
+    pub blinded_paths: Option<BlindedPaths>,
     pub users: Vec<User>,
-    pub lnd: Lnd,
+    pub backend: Option<BackendKind>,
+    pub lnd: Option<Lnd>,
+    pub lnd_nodes: Option<Vec<LndNode>>,
+    pub health_check_interval_secs: Option<u64>,
+    pub startup_retry_initial_secs: Option<u64>,
+    pub startup_retry_max_secs: Option<u64>,
+    /// How often cert and macaroon files are checked for changes.
+    pub credential_watch_interval_secs: Option<u64>,
+    pub cln: Option<Cln>,
+    pub nwc: Option<Nwc>,
+    pub backends: Option<Vec<NamedBackend>>,
+    /// File holding pending zaps and settlement progress; defaults to `state.json` next to the
+    /// config file.
+    pub state_path: Option<String>,
+    /// Bearer token for the `/admin` endpoints. They stay closed without it or `admin_keys`.
+    pub admin_token: Option<String>,
+    /// Hex LNURL-auth linking keys of the wallets allowed to sign in as admin.
+    pub admin_keys: Option<Vec<String>>,
+    pub admin_session_ttl_secs: Option<u64>,
+    /// File holding withdraw vouchers; defaults to `vouchers.json` next to the config file.
+    pub voucher_path: Option<String>,
     pub server: Server,
     pub nostr: Nostr,
 }
@@ -94,3 +294,40 @@ pub fn get_config() -> &'static Config {
         }
     })
 }
+
+/// Config for tests, with the linking key of the LUD-04 test vector in `server::lnurl_auth`
+/// as the only admin key.
+#[cfg(test)]
+const TEST_CONFIG: &str = r#"
+domain = "example.com"
+users = []
+admin_token = "test admin token"
+admin_keys = ["02e10e6188de29e212ef7d74644b9822d403283127432fa115bf3cbde61aead0dd"]
+
+[server]
+host = "127.0.0.1"
+port = 6000
+
+[nostr]
+private_key = "test"
+"#;
+
+/// Loads `TEST_CONFIG` in place of the config file.
+#[cfg(test)]
+pub fn init_test_config() -> &'static Config {
+    CONFIG.get_or_init(|| toml::from_str(TEST_CONFIG).expect("test config parses"))
+}
+
+pub fn get_state_path() -> PathBuf {
+    match &get_config().state_path {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&*CONFIG_PATH).with_file_name("state.json"),
+    }
+}
+
+pub fn get_voucher_path() -> PathBuf {
+    match &get_config().voucher_path {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&*CONFIG_PATH).with_file_name("vouchers.json"),
+    }
+}
@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use futures::future::select_ok;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
+
+use crate::credentials::{
+    lightning_backend::{
+        CreatedInvoice, InvoiceError, InvoiceRequest, InvoiceStatus, LightningBackend, NodeInfo,
+        NodeReload, PaidInvoice, PaymentFailed, SettledInvoice,
+    },
+    macaroon_audit::is_permission_denied,
+};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct FailoverNode {
+    pub name: String,
+    pub priority: u32,
+    pub backend: Arc<dyn LightningBackend>,
+    healthy: AtomicBool,
+}
+
+impl FailoverNode {
+    pub fn new(name: String, priority: u32, backend: Arc<dyn LightningBackend>) -> Self {
+        FailoverNode {
+            name,
+            priority,
+            backend,
+            // Nodes are tried until the first health check says otherwise.
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        if was_healthy && !healthy {
+            warn!(target: "credentials::failover", "Node {} is unhealthy", self.name);
+        } else if !was_healthy && healthy {
+            info!(target: "credentials::failover", "Node {} is healthy again", self.name);
+        }
+    }
+}
+
+/// Whether `node` answers and is synced to the chain. Probing with `get_node_info` leaves no
+/// invoices behind on the node.
+async fn check_node(node: &FailoverNode) -> bool {
+    match timeout(HEALTH_CHECK_TIMEOUT, node.backend.get_node_info()).await {
+        Ok(Ok(info)) => {
+            if !info.synced_to_chain {
+                warn!(target: "credentials::failover", "Node {} is not synced to the chain", node.name);
+            }
+            info.synced_to_chain
+        }
+        // A macaroon limited to invoices cannot read node info, but the node answered.
+        Ok(Err(e)) if is_permission_denied(&e) => {
+            debug!(target: "credentials::failover", "Node {} is reachable, but its macaroon cannot read node info", node.name);
+            true
+        }
+        Ok(Err(e)) => {
+            warn!(target: "credentials::failover", "Health check failed on {}: {}", node.name, e);
+            false
+        }
+        Err(_) => {
+            warn!(target: "credentials::failover", "Health check timed out on {}", node.name);
+            false
+        }
+    }
+}
+
+/// Spreads invoices over several nodes: each invoice goes to the healthiest node with the
+/// lowest priority number, and its settlement is watched on that same node.
+pub struct FailoverBackend {
+    nodes: Vec<FailoverNode>,
+    /// Issuing node per payment hash, kept until the invoice could no longer be paid.
+    issued_by: Mutex<HashMap<Vec<u8>, (usize, Instant)>>,
+}
+
+impl FailoverBackend {
+    pub fn new(mut nodes: Vec<FailoverNode>) -> Self {
+        nodes.sort_by_key(|node| node.priority);
+
+        FailoverBackend {
+            nodes,
+            issued_by: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks forever that every node is reachable and synced to the chain.
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration) {
+        let failover = Arc::clone(self);
+
+        tokio::spawn(async move {
+            loop {
+                for node in &failover.nodes {
+                    node.set_healthy(check_node(node).await);
+                }
+
+                sleep(interval).await;
+            }
+        });
+    }
+
+    /// Healthy nodes in priority order, followed by the unhealthy ones as a last resort.
+    fn candidates(&self) -> Vec<(usize, &FailoverNode)> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .enumerate()
+            .partition(|(_, node)| node.is_healthy());
+        healthy.extend(unhealthy);
+        healthy
+    }
+}
+
+#[async_trait]
+impl LightningBackend for FailoverBackend {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error> {
+        let mut last_error = anyhow!("NoLightningNodesConfigured");
+
+        for (index, node) in self.candidates() {
+            match node.backend.create_invoice(request.clone()).await {
+                Ok(invoice) => {
+                    let expires_at = Instant::now() + Duration::from_secs(request.expiry as u64);
+                    let mut issued_by = self.issued_by.lock().expect("failover lock poisoned");
+                    issued_by.retain(|_, (_, expiry)| *expiry > Instant::now());
+                    issued_by.insert(invoice.payment_hash.clone(), (index, expires_at));
+
+                    return Ok(invoice);
+                }
+                Err(e) => {
+                    warn!(target: "credentials::failover", "Failed to create invoice on {}: {}", node.name, e);
+                    // A refusal can be specific to the invoice; only an unreachable node is down.
+                    if matches!(e.downcast_ref(), Some(InvoiceError::NodeOffline)) {
+                        node.set_healthy(false);
+                    }
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn supports_blinded_paths(&self) -> bool {
+        self.nodes
+            .iter()
+            .all(|node| node.backend.supports_blinded_paths())
+    }
+
+    fn supports_chosen_preimage(&self) -> bool {
+        self.nodes
+            .iter()
+            .all(|node| node.backend.supports_chosen_preimage())
+    }
+
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error> {
+        let index = self
+            .issued_by
+            .lock()
+            .expect("failover lock poisoned")
+            .get(payment_hash)
+            .map(|(index, _)| *index);
+
+        match index {
+            Some(index) => {
+                self.nodes[index]
+                    .backend
+                    .wait_for_settlement(payment_hash)
+                    .await
+            }
+            // Invoices issued before a restart are watched on every node; only the issuing
+            // node knows them.
+            None if !self.nodes.is_empty() => {
+                let watchers = self
+                    .nodes
+                    .iter()
+                    .map(|node| node.backend.wait_for_settlement(payment_hash));
+                let (invoice, _) = select_ok(watchers).await?;
+                Ok(invoice)
+            }
+            None => Err(anyhow!("NoLightningNodesConfigured")),
+        }
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error> {
+        let index = self
+            .issued_by
+            .lock()
+            .expect("failover lock poisoned")
+            .get(payment_hash)
+            .map(|(index, _)| *index);
+
+        if let Some(index) = index {
+            return self.nodes[index].backend.lookup_invoice(payment_hash).await;
+        }
+
+        // Only the issuing node knows the invoice, so the first answer wins.
+        let mut last_error = anyhow!("NoLightningNodesConfigured");
+        for node in &self.nodes {
+            match node.backend.lookup_invoice(payment_hash).await {
+                Ok(invoice) => return Ok(invoice),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+        let mut last_error = anyhow!("NoLightningNodesConfigured");
+
+        for node in &self.nodes {
+            match node.backend.test_invoice().await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!(target: "credentials::failover", "Invoice test failed on {}: {}", node.name, e);
+                    node.set_healthy(false);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+        let mut last_error = anyhow!("NoLightningNodesConfigured");
+
+        for (_, node) in self.candidates() {
+            match node.backend.get_node_info().await {
+                Ok(info) => return Ok(info),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn get_inbound_capacity(&self) -> Result<Option<i64>, anyhow::Error> {
+        let mut last_error = anyhow!("NoLightningNodesConfigured");
+
+        // Invoices go to the first healthy node, so its capacity is the one that matters.
+        for (_, node) in self.candidates() {
+            match node.backend.get_inbound_capacity().await {
+                Ok(capacity) => return Ok(capacity),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Moves on to the next node only when a payment certainly failed, so an invoice is never
+    /// paid twice.
+    async fn pay_invoice(
+        &self,
+        payment_request: &str,
+        max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        let mut last_error = anyhow!("NoLightningNodesConfigured");
+
+        for (_, node) in self.candidates() {
+            match node
+                .backend
+                .pay_invoice(payment_request, max_fee_msat)
+                .await
+            {
+                Ok(paid) => return Ok(paid),
+                Err(e) if PaymentFailed::is_cause_of(&e) => {
+                    warn!(target: "credentials::failover", "Payment failed on {}: {}", node.name, e);
+                    last_error = e;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn audit_permissions(&self, _name: &str) -> Result<(), anyhow::Error> {
+        for node in &self.nodes {
+            if let Err(e) = node.backend.audit_permissions(&node.name).await {
+                warn!(target: "credentials::failover", "Failed to audit the macaroon for {}: {}", node.name, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn reload_credentials(&self) -> Result<bool, anyhow::Error> {
+        let mut reloaded = false;
+        let mut failed = vec![];
+
+        for (node, result) in self.reload_node_credentials().await {
+            match result {
+                Ok(node_reloaded) => reloaded |= node_reloaded,
+                Err(e) => failed.push(format!("{}: {}", node.unwrap_or_default(), e)),
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(reloaded)
+        } else {
+            Err(anyhow!("{}", failed.join("; ")))
+        }
+    }
+
+    /// Every node reloads on its own, so one failing does not hide another's reload.
+    async fn reload_node_credentials(&self) -> Vec<NodeReload> {
+        let mut results = vec![];
+
+        for node in &self.nodes {
+            let result = node.backend.reload_credentials().await;
+            results.push((Some(node.name.clone()), result));
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node that answers `get_node_info` with `node_info`, and nothing else.
+    struct StubNode {
+        node_info: fn() -> Result<NodeInfo, anyhow::Error>,
+    }
+
+    #[async_trait]
+    impl LightningBackend for StubNode {
+        async fn create_invoice(
+            &self,
+            _request: InvoiceRequest,
+        ) -> Result<CreatedInvoice, anyhow::Error> {
+            Err(anyhow!("NotImplemented"))
+        }
+
+        async fn wait_for_settlement(
+            &self,
+            _payment_hash: &[u8],
+        ) -> Result<SettledInvoice, anyhow::Error> {
+            Err(anyhow!("NotImplemented"))
+        }
+
+        async fn lookup_invoice(
+            &self,
+            _payment_hash: &[u8],
+        ) -> Result<InvoiceStatus, anyhow::Error> {
+            Err(anyhow!("NotImplemented"))
+        }
+
+        async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+            Ok(())
+        }
+
+        async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+            (self.node_info)()
+        }
+    }
+
+    fn node(node_info: fn() -> Result<NodeInfo, anyhow::Error>) -> FailoverNode {
+        FailoverNode::new("stub".to_string(), 0, Arc::new(StubNode { node_info }))
+    }
+
+    fn node_info(synced_to_chain: bool) -> Result<NodeInfo, anyhow::Error> {
+        Ok(NodeInfo {
+            pubkey: String::new(),
+            alias: String::new(),
+            version: String::new(),
+            block_height: 0,
+            synced_to_chain,
+            num_active_channels: Some(1),
+        })
+    }
+
+    #[tokio::test]
+    async fn node_denying_node_info_is_healthy() {
+        let denied = node(|| Err(anyhow!("status: Unknown, message: \"permission denied\"")));
+        assert!(check_node(&denied).await);
+    }
+
+    #[tokio::test]
+    async fn synced_node_is_healthy() {
+        assert!(check_node(&node(|| node_info(true))).await);
+        assert!(!check_node(&node(|| node_info(false))).await);
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_is_unhealthy() {
+        let offline = node(|| Err(InvoiceError::offline("connection refused")));
+        assert!(!check_node(&offline).await);
+    }
+}
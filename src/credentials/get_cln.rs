@@ -0,0 +1,540 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::mpsc,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::Cln,
+    credentials::lightning_backend::{
+        CreatedInvoice, InvoiceError, InvoiceRequest, InvoiceStatus, LightningBackend, NodeInfo,
+        PaidInvoice, PaymentFailed, SettledInvoice,
+    },
+};
+
+/// `pay` fails with this code while the payment may still complete.
+const PAY_IN_PROGRESS: i64 = 200;
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct InvoiceResponse {
+    bolt11: String,
+    payment_hash: String,
+    warning_capacity: Option<String>,
+    warning_offline: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListedInvoice {
+    label: String,
+    status: String,
+    bolt11: Option<String>,
+    payment_preimage: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListInvoicesResponse {
+    invoices: Vec<ListedInvoice>,
+}
+
+#[derive(Deserialize)]
+struct WaitInvoiceResponse {
+    payment_hash: String,
+    status: String,
+    bolt11: Option<String>,
+    payment_preimage: Option<String>,
+    paid_at: Option<i64>,
+    pay_index: Option<u64>,
+}
+
+impl WaitInvoiceResponse {
+    fn into_settled(self) -> Result<SettledInvoice, anyhow::Error> {
+        if self.status != "paid" {
+            return Err(anyhow!("ClnInvoiceNotPaid: {}", self.status));
+        }
+
+        Ok(SettledInvoice {
+            payment_hash: hex::decode(self.payment_hash)?,
+            payment_request: self.bolt11.unwrap_or_default(),
+            preimage: hex::decode(self.payment_preimage.unwrap_or_default())?,
+            settle_date: self.paid_at.unwrap_or_default(),
+            settle_index: self.pay_index.unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct PayResponse {
+    payment_hash: String,
+    payment_preimage: String,
+    status: String,
+    amount_msat: i64,
+    amount_sent_msat: i64,
+}
+
+#[derive(Deserialize)]
+struct GetInfoResponse {
+    id: String,
+    alias: String,
+    version: String,
+    blockheight: u32,
+    num_active_channels: u32,
+    warning_bitcoind_sync: Option<String>,
+    warning_lightningd_sync: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PeerChannel {
+    state: String,
+    peer_connected: bool,
+    receivable_msat: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ListPeerChannelsResponse {
+    channels: Vec<PeerChannel>,
+}
+
+/// Core Lightning over its JSON-RPC unix socket.
+pub struct ClnBackend {
+    socket_path: String,
+    next_id: AtomicU64,
+}
+
+impl ClnBackend {
+    pub fn connect(cln_config: &Cln) -> Self {
+        ClnBackend {
+            socket_path: cln_config.socket_path.clone(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn new_label(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        format!(
+            "rustdress-{}-{}",
+            nanos,
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Sends one request on a fresh connection and reads until a full response is parsed.
+    /// CLN does not delimit responses, so the buffer is re-parsed after every read.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, anyhow::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!(target: "credentials::get_cln", "Calling {} (id {})", method, id);
+
+        let mut stream = UnixStream::connect(&self.socket_path)
+            .await
+            .map_err(InvoiceError::offline)?;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(anyhow!("ClnClosedConnectionBeforeResponse"));
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+
+            let response = match serde_json::from_slice::<RpcResponse<T>>(&buffer) {
+                Ok(response) => response,
+                Err(e) if e.is_eof() => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some(error) = response.error {
+                let message = format!("Cln{}Failed: {}", method, error);
+                return Err(anyhow::Error::new(error).context(message));
+            }
+
+            return response
+                .result
+                .ok_or_else(|| anyhow!("ClnResponseMissingResultFor{}", method));
+        }
+    }
+}
+
+#[async_trait]
+impl LightningBackend for ClnBackend {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error> {
+        // CLN hashes the description itself; `deschashonly` keeps only the hash in the bolt11.
+        let mut params = json!({
+            "amount_msat": request.amount_msat,
+            "label": self.new_label(),
+            "description": request.description,
+            "expiry": request.expiry,
+            "exposeprivatechannels": request.private,
+            "deschashonly": true,
+        });
+        if let Some(preimage) = request.preimage {
+            params["preimage"] = Value::String(hex::encode(preimage));
+        }
+
+        let invoice: InvoiceResponse = self.call("invoice", params).await?;
+
+        // The invoice exists either way, but no payer could route to it.
+        if let Some(warning) = invoice.warning_capacity {
+            return Err(
+                anyhow::Error::new(InvoiceError::InsufficientInboundLiquidity).context(warning),
+            );
+        }
+
+        // The channels with enough capacity are there, only their peers are disconnected.
+        if let Some(warning) = invoice.warning_offline {
+            return Err(InvoiceError::offline(warning));
+        }
+
+        Ok(CreatedInvoice {
+            payment_hash: hex::decode(invoice.payment_hash)?,
+            payment_request: invoice.bolt11,
+        })
+    }
+
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error> {
+        let listed: ListInvoicesResponse = self
+            .call(
+                "listinvoices",
+                json!({ "payment_hash": hex::encode(payment_hash) }),
+            )
+            .await?;
+
+        let label = match listed.invoices.into_iter().next() {
+            Some(invoice) => invoice.label,
+            None => return Err(anyhow!("ClnInvoiceNotFound")),
+        };
+
+        // Blocks until the invoice is paid; CLN answers with an error once it expires.
+        let invoice: WaitInvoiceResponse =
+            self.call("waitinvoice", json!({ "label": label })).await?;
+
+        invoice.into_settled()
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error> {
+        let listed: ListInvoicesResponse = self
+            .call(
+                "listinvoices",
+                json!({ "payment_hash": hex::encode(payment_hash) }),
+            )
+            .await?;
+
+        let invoice = listed
+            .invoices
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("ClnInvoiceNotFound"))?;
+        let settled = invoice.status == "paid";
+
+        Ok(InvoiceStatus {
+            payment_request: invoice.bolt11.unwrap_or_default(),
+            settled,
+            preimage: match invoice.payment_preimage {
+                Some(preimage) if settled => Some(hex::decode(preimage)?),
+                _ => None,
+            },
+        })
+    }
+
+    fn supports_settlement_feed(&self) -> bool {
+        true
+    }
+
+    /// CLN numbers paid invoices with `pay_index`, and `waitanyinvoice` returns the first one
+    /// paid after the given index, waiting if there is none yet.
+    async fn subscribe_settlements(
+        &self,
+        settle_index: u64,
+        settlements: mpsc::Sender<SettledInvoice>,
+    ) -> Result<(), anyhow::Error> {
+        let mut pay_index = settle_index;
+
+        loop {
+            let invoice: WaitInvoiceResponse = self
+                .call("waitanyinvoice", json!({ "lastpay_index": pay_index }))
+                .await?;
+            // Pay indexes have no gaps, so the index moves past an invoice that cannot be read
+            // instead of asking for it again.
+            pay_index = invoice.pay_index.unwrap_or(pay_index + 1);
+
+            match invoice.into_settled() {
+                Ok(settled) => {
+                    if settlements.send(settled).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    warn!(target: "credentials::get_cln", "Skipping invoice at pay index {}: {}", pay_index, e)
+                }
+            }
+        }
+    }
+
+    async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+        self.call::<InvoiceResponse>(
+            "invoice",
+            json!({
+                "amount_msat": 5000,
+                "label": self.new_label(),
+                "description": "rustdress test invoice",
+                "expiry": 100,
+            }),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+        let info: GetInfoResponse = self.call("getinfo", json!({})).await?;
+
+        Ok(NodeInfo {
+            pubkey: info.id,
+            alias: info.alias,
+            version: info.version,
+            block_height: info.blockheight,
+            synced_to_chain: info.warning_bitcoind_sync.is_none()
+                && info.warning_lightningd_sync.is_none(),
+            num_active_channels: Some(info.num_active_channels),
+        })
+    }
+
+    async fn pay_invoice(
+        &self,
+        payment_request: &str,
+        max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        let result = self
+            .call::<PayResponse>(
+                "pay",
+                json!({ "bolt11": payment_request, "maxfee": max_fee_msat }),
+            )
+            .await;
+
+        let paid = match result {
+            Ok(paid) => paid,
+            Err(e)
+                if e.downcast_ref::<RpcError>()
+                    .is_some_and(|error| error.code != PAY_IN_PROGRESS) =>
+            {
+                return Err(PaymentFailed::with_cause(e));
+            }
+            Err(e) => return Err(e),
+        };
+
+        if paid.status != "complete" {
+            return Err(anyhow!("ClnPaymentNotComplete: {}", paid.status));
+        }
+
+        Ok(PaidInvoice {
+            payment_hash: hex::decode(paid.payment_hash)?,
+            preimage: hex::decode(paid.payment_preimage)?,
+            fee_msat: paid.amount_sent_msat - paid.amount_msat,
+        })
+    }
+
+    async fn get_inbound_capacity(&self) -> Result<Option<i64>, anyhow::Error> {
+        let response: ListPeerChannelsResponse = self.call("listpeerchannels", json!({})).await?;
+
+        // `receivable_msat` already leaves out the peer's reserve and pending HTLCs.
+        let inbound_msat = response
+            .channels
+            .iter()
+            .filter(|channel| channel.peer_connected && channel.state == "CHANNELD_NORMAL")
+            .map(|channel| channel.receivable_msat.unwrap_or_default() as i64)
+            .sum();
+
+        Ok(Some(inbound_msat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{net::UnixListener, time::sleep};
+
+    use super::*;
+
+    /// A CLN socket answering each request with what `respond` returns for its method and
+    /// params, written a few bytes at a time so every response spans several reads.
+    fn fake_cln(respond: fn(&str, &Value) -> Value) -> ClnBackend {
+        let path = std::env::temp_dir().join(format!(
+            "rustdress-cln-{}.sock",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let listener = UnixListener::bind(&path).expect("fake socket binds");
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    let request: Value = loop {
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                        }
+                        if let Ok(request) = serde_json::from_slice(&buffer) {
+                            break request;
+                        }
+                    };
+
+                    let method = request["method"].as_str().unwrap_or_default();
+                    let mut response = respond(method, &request["params"]);
+                    response["jsonrpc"] = json!("2.0");
+                    response["id"] = request["id"].clone();
+
+                    let response = serde_json::to_vec(&response).expect("response serializes");
+                    for piece in response.chunks(7) {
+                        if stream.write_all(piece).await.is_err() {
+                            return;
+                        }
+                        sleep(Duration::from_millis(1)).await;
+                    }
+                });
+            }
+        });
+
+        ClnBackend::connect(&Cln {
+            socket_path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn reads_response_split_across_reads() {
+        let cln = fake_cln(|method, _| match method {
+            "getinfo" => json!({ "result": {
+                "id": "02abcdef",
+                "alias": "fake-cln",
+                "version": "v24.02",
+                "blockheight": 800000,
+                "num_active_channels": 3,
+            }}),
+            _ => json!({ "error": { "code": -32601, "message": "Unknown command" } }),
+        });
+
+        let info = cln.get_node_info().await.expect("getinfo answered");
+        assert_eq!(info.alias, "fake-cln");
+        assert_eq!(info.block_height, 800000);
+        assert_eq!(info.num_active_channels, Some(3));
+        assert!(info.synced_to_chain);
+    }
+
+    #[tokio::test]
+    async fn resumes_settlements_from_lastpay_index() {
+        // Pay index 7 belongs to an invoice that is not paid, which is skipped.
+        let cln = fake_cln(|method, params| {
+            assert_eq!(method, "waitanyinvoice");
+            let pay_index = params["lastpay_index"].as_u64().unwrap_or_default() + 1;
+            json!({ "result": {
+                "payment_hash": format!("{:064x}", pay_index),
+                "status": if pay_index == 7 { "expired" } else { "paid" },
+                "bolt11": "lnbcrt10n1fake",
+                "payment_preimage": format!("{:064x}", pay_index + 100),
+                "paid_at": 1700000000,
+                "pay_index": pay_index,
+            }})
+        });
+
+        let (sender, mut receiver) = mpsc::channel(1);
+        tokio::spawn(async move { cln.subscribe_settlements(5, sender).await });
+
+        let first = receiver.recv().await.expect("first settlement");
+        assert_eq!(first.settle_index, 6);
+        assert_eq!(
+            first.payment_hash,
+            hex::decode(format!("{:064x}", 6)).unwrap()
+        );
+
+        let second = receiver.recv().await.expect("second settlement");
+        assert_eq!(second.settle_index, 8);
+    }
+
+    #[tokio::test]
+    async fn pay_in_progress_is_not_a_failure() {
+        let cln =
+            fake_cln(|_, _| json!({ "error": { "code": 200, "message": "Payment in progress" } }));
+
+        let error = cln.pay_invoice("lnbcrt10n1fake", 1000).await.unwrap_err();
+        assert!(!PaymentFailed::is_cause_of(&error));
+    }
+
+    #[tokio::test]
+    async fn pay_errors_with_other_codes_are_failures() {
+        let cln = fake_cln(
+            |_, _| json!({ "error": { "code": 210, "message": "Ran out of routes to try" } }),
+        );
+
+        let error = cln.pay_invoice("lnbcrt10n1fake", 1000).await.unwrap_err();
+        assert!(PaymentFailed::is_cause_of(&error));
+    }
+
+    #[tokio::test]
+    async fn completed_payment_reports_fee() {
+        let cln = fake_cln(|_, _| {
+            json!({ "result": {
+                "payment_hash": "11".repeat(32),
+                "payment_preimage": "22".repeat(32),
+                "status": "complete",
+                "amount_msat": 1000,
+                "amount_sent_msat": 1012,
+            }})
+        });
+
+        let paid = cln.pay_invoice("lnbcrt10n1fake", 1000).await.expect("paid");
+        assert_eq!(paid.preimage, vec![0x22; 32]);
+        assert_eq!(paid.fee_msat, 12);
+    }
+}
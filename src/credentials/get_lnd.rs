@@ -1,25 +1,430 @@
-use crate::credentials::{get_cert::get_cert, get_macaroon::get_macaroon, get_socket::get_socket};
-use lnd_grpc_rust::{lnrpc::Invoice, LndClient};
+use std::{error::Error, time::Duration};
 
-pub async fn get_lnd() -> LndClient {
-    let cert = get_cert();
-    let macaroon = get_macaroon();
-    let socket = get_socket();
+use crate::{
+    config::Lnd,
+    credentials::{
+        get_cert::get_cert,
+        get_macaroon::get_macaroon,
+        get_socket::get_socket,
+        lightning_backend::{
+            CreatedInvoice, InvoiceError, InvoiceRequest, InvoiceStatus, LightningBackend,
+            NodeInfo, PaidInvoice, PaymentFailed, SettledInvoice,
+        },
+        macaroon_audit::Permission,
+        reload::CredentialFiles,
+    },
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use lnd_grpc_rust::{
+    LndClient, LndClientError, LndInvoicesClient, LndLightningClient, LndRouterClient,
+    invoicesrpc::SubscribeSingleInvoiceRequest,
+    lnrpc::{
+        BakeMacaroonRequest, BlindedPathConfig, CheckMacPermRequest, GetInfoRequest, Invoice,
+        InvoiceSubscription, ListChannelsRequest, MacaroonPermission, PaymentFailureReason,
+        PaymentHash, invoice::InvoiceState, payment::PaymentStatus,
+    },
+    routerrpc::SendPaymentRequest,
+};
+use tokio::{
+    sync::{Mutex, mpsc},
+    time::timeout,
+};
+use tonic::Code;
+use tracing::{debug, info, warn};
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long LND keeps trying routes for a payment before failing it.
+const PAYMENT_TIMEOUT_SECS: i32 = 60;
+
+pub async fn get_lnd(lnd_config: &Lnd) -> Result<LndClient, anyhow::Error> {
+    let cert = get_cert(lnd_config)?;
+    let macaroon = get_macaroon(lnd_config)?;
+    let socket = get_socket(lnd_config);
 
     lnd_grpc_rust::connect(cert, macaroon, socket)
         .await
-        .expect("FailedToAuthenticateToLnd")
+        .map_err(|e| anyhow!("FailedToAuthenticateToLnd: {}", e))
+}
+
+/// Whether a call failed because the connection broke rather than because LND refused it.
+/// Statuses LND sends back carry no source; ones raised by the transport do.
+fn is_transport_error(status: &LndClientError) -> bool {
+    status.code() == Code::Unavailable || status.source().is_some()
+}
+
+fn macaroon_permissions(permissions: &[Permission]) -> Vec<MacaroonPermission> {
+    permissions
+        .iter()
+        .map(|permission| MacaroonPermission {
+            entity: permission.entity.to_string(),
+            action: permission.action.to_string(),
+        })
+        .collect()
 }
 
-pub async fn test_invoice(mut client: LndClient) -> Result<(), anyhow::Error> {
-    client
-        .lightning()
-        .add_invoice(Invoice {
+fn settled_invoice(invoice: Invoice) -> SettledInvoice {
+    SettledInvoice {
+        payment_hash: invoice.r_hash,
+        payment_request: invoice.payment_request,
+        preimage: invoice.r_preimage,
+        settle_date: invoice.settle_date,
+        settle_index: invoice.settle_index,
+    }
+}
+
+#[derive(Clone)]
+struct LndClients {
+    lightning: LndLightningClient,
+    invoices: LndInvoicesClient,
+    router: LndRouterClient,
+}
+
+/// LND over gRPC. Clients share one connection, made on first use and remade after the
+/// transport breaks.
+pub struct LndBackend {
+    lnd_config: Lnd,
+    credential_files: CredentialFiles,
+    clients: Mutex<Option<LndClients>>,
+}
+
+impl LndBackend {
+    pub fn new(lnd_config: &Lnd) -> Self {
+        LndBackend {
+            lnd_config: lnd_config.clone(),
+            credential_files: CredentialFiles::new(lnd_config),
+            clients: Mutex::new(None),
+        }
+    }
+
+    async fn clients(&self) -> Result<LndClients, anyhow::Error> {
+        let mut clients = self.clients.lock().await;
+
+        if let Some(clients) = clients.as_ref() {
+            return Ok(clients.clone());
+        }
+
+        let mut lnd = get_lnd(&self.lnd_config)
+            .await
+            .map_err(InvoiceError::offline)?;
+        info!(target: "credentials::get_lnd", "Opened connection to LND at {}", self.lnd_config.socket);
+
+        let connected = LndClients {
+            lightning: lnd.lightning().clone(),
+            invoices: lnd.invoices().clone(),
+            router: lnd.router().clone(),
+        };
+        *clients = Some(connected.clone());
+
+        Ok(connected)
+    }
+
+    /// Drops the shared connection when `result` failed in transport, so the next call
+    /// reconnects.
+    async fn check<T>(&self, result: Result<T, LndClientError>) -> Result<T, anyhow::Error> {
+        let status = match result {
+            Ok(value) => return Ok(value),
+            Err(status) => status,
+        };
+
+        if is_transport_error(&status) {
+            warn!(target: "credentials::get_lnd", "Lost connection to LND, reconnecting on next call: {}", status);
+            *self.clients.lock().await = None;
+            return Err(InvoiceError::offline(status));
+        }
+
+        if status.message().contains("wallet locked") {
+            return Err(InvoiceError::offline(status));
+        }
+
+        Err(status.into())
+    }
+
+    /// Runs a unary call with a deadline, so an unresponsive node fails the request instead of
+    /// holding it open.
+    async fn call<T>(
+        &self,
+        request: impl Future<Output = Result<T, LndClientError>>,
+    ) -> Result<T, anyhow::Error> {
+        match timeout(RPC_TIMEOUT, request).await {
+            Ok(result) => self.check(result).await,
+            Err(_) => Err(InvoiceError::offline("LndCallTimedOut")),
+        }
+    }
+}
+
+#[async_trait]
+impl LightningBackend for LndBackend {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        let result = self
+            .call(lightning.add_invoice(Invoice {
+                description_hash: request.description_hash,
+                expiry: request.expiry,
+                memo: request.memo,
+                private: request.private,
+                value_msat: request.amount_msat,
+                r_preimage: request.preimage.map(Vec::from).unwrap_or_default(),
+                is_blinded: request.blinded_paths.is_some(),
+                blinded_path_config: request.blinded_paths.map(|paths| BlindedPathConfig {
+                    min_num_real_hops: paths.min_real_hops,
+                    num_hops: paths.num_hops,
+                    max_num_paths: paths.num_paths,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner();
+
+        Ok(CreatedInvoice {
+            payment_hash: result.r_hash,
+            payment_request: result.payment_request,
+        })
+    }
+
+    fn supports_blinded_paths(&self) -> bool {
+        true
+    }
+
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error> {
+        let mut invoices = self.clients().await?.invoices;
+        let mut invoice_subscription = self
+            .check(
+                invoices
+                    .subscribe_single_invoice(SubscribeSingleInvoiceRequest {
+                        r_hash: payment_hash.to_vec(),
+                    })
+                    .await,
+            )
+            .await?
+            .into_inner();
+        debug!(target: "credentials::get_lnd", "Successfully subscribed to invoice updates");
+
+        while let Some(invoice) = self.check(invoice_subscription.message().await).await? {
+            if let Ok(state) = InvoiceState::try_from(invoice.state) {
+                debug!(target: "credentials::get_lnd", "Invoice state update: {:?}", state);
+
+                if state == InvoiceState::Settled {
+                    return Ok(settled_invoice(invoice));
+                }
+
+                if state == InvoiceState::Canceled {
+                    return Err(anyhow!("InvoiceWasCanceled"));
+                }
+            }
+        }
+
+        Err(anyhow!("InvoiceSubscriptionEnded"))
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        let invoice = self
+            .call(lightning.lookup_invoice(PaymentHash {
+                r_hash: payment_hash.to_vec(),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner();
+        let settled = invoice.state == InvoiceState::Settled as i32;
+
+        Ok(InvoiceStatus {
+            payment_request: invoice.payment_request,
+            settled,
+            preimage: settled.then_some(invoice.r_preimage),
+        })
+    }
+
+    fn supports_settlement_feed(&self) -> bool {
+        true
+    }
+
+    async fn subscribe_settlements(
+        &self,
+        settle_index: u64,
+        settlements: mpsc::Sender<SettledInvoice>,
+    ) -> Result<(), anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        let mut subscription = self
+            .check(
+                lightning
+                    .subscribe_invoices(InvoiceSubscription {
+                        settle_index,
+                        ..Default::default()
+                    })
+                    .await,
+            )
+            .await?
+            .into_inner();
+        debug!(target: "credentials::get_lnd", "Subscribed to settlements after index {}", settle_index);
+
+        // The stream also reports newly added invoices, which are skipped.
+        while let Some(invoice) = self.check(subscription.message().await).await? {
+            if invoice.state != InvoiceState::Settled as i32 {
+                continue;
+            }
+
+            if settlements.send(settled_invoice(invoice)).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        self.call(lightning.add_invoice(Invoice {
             value: 5,
             expiry: 100,
             ..Default::default()
-        })
+        }))
         .await?;
 
-    Ok(())
+        Ok(())
+    }
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        let info = self
+            .call(lightning.get_info(GetInfoRequest {}))
+            .await?
+            .into_inner();
+
+        Ok(NodeInfo {
+            pubkey: info.identity_pubkey,
+            alias: info.alias,
+            version: info.version,
+            block_height: info.block_height,
+            synced_to_chain: info.synced_to_chain,
+            num_active_channels: Some(info.num_active_channels),
+        })
+    }
+
+    async fn get_inbound_capacity(&self) -> Result<Option<i64>, anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        let channels = self
+            .call(lightning.list_channels(ListChannelsRequest {
+                active_only: true,
+                ..Default::default()
+            }))
+            .await?
+            .into_inner()
+            .channels;
+
+        // The peer has to keep its channel reserve, so that part of its balance cannot be sent.
+        let inbound_sat: i64 = channels
+            .iter()
+            .map(|channel| {
+                let reserve_sat = channel
+                    .remote_constraints
+                    .as_ref()
+                    .map(|constraints| constraints.chan_reserve_sat as i64)
+                    .unwrap_or_default();
+                (channel.remote_balance - reserve_sat).max(0)
+            })
+            .sum();
+
+        Ok(Some(inbound_sat * 1000))
+    }
+
+    async fn pay_invoice(
+        &self,
+        payment_request: &str,
+        max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        let mut router = self.clients().await?.router;
+        let request = router.send_payment_v2(SendPaymentRequest {
+            payment_request: payment_request.to_string(),
+            fee_limit_msat: max_fee_msat,
+            timeout_seconds: PAYMENT_TIMEOUT_SECS,
+            no_inflight_updates: true,
+            ..Default::default()
+        });
+
+        let mut updates = match timeout(RPC_TIMEOUT, request).await {
+            // LND refused before sending anything, e.g. for an expired or already paid invoice.
+            Ok(Err(status)) if !is_transport_error(&status) => {
+                return Err(PaymentFailed::with_cause(status));
+            }
+            Ok(result) => self.check(result).await?.into_inner(),
+            Err(_) => return Err(InvoiceError::offline("LndCallTimedOut")),
+        };
+
+        while let Some(payment) = self.check(updates.message().await).await? {
+            match PaymentStatus::try_from(payment.status) {
+                Ok(PaymentStatus::Succeeded) => {
+                    return Ok(PaidInvoice {
+                        payment_hash: hex::decode(payment.payment_hash)?,
+                        preimage: hex::decode(payment.payment_preimage)?,
+                        fee_msat: payment.fee_msat,
+                    });
+                }
+                Ok(PaymentStatus::Failed) => {
+                    let reason = PaymentFailureReason::try_from(payment.failure_reason)
+                        .map(|reason| reason.as_str_name())
+                        .unwrap_or("FAILURE_REASON_UNKNOWN");
+                    return Err(PaymentFailed::with_cause(format!(
+                        "LndPaymentFailed: {}",
+                        reason
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("PaymentUpdatesEnded"))
+    }
+
+    async fn has_permissions(
+        &self,
+        permissions: &[Permission],
+    ) -> Result<Option<bool>, anyhow::Error> {
+        let macaroon = hex::decode(get_macaroon(&self.lnd_config)?)?;
+        let mut lightning = self.clients().await?.lightning;
+        let response = self
+            .call(lightning.check_macaroon_permissions(CheckMacPermRequest {
+                macaroon,
+                permissions: macaroon_permissions(permissions),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner();
+
+        Ok(Some(response.valid))
+    }
+
+    async fn bake_macaroon(&self, permissions: &[Permission]) -> Result<String, anyhow::Error> {
+        let mut lightning = self.clients().await?.lightning;
+        let response = self
+            .call(lightning.bake_macaroon(BakeMacaroonRequest {
+                permissions: macaroon_permissions(permissions),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner();
+
+        Ok(response.macaroon)
+    }
+
+    async fn reload_credentials(&self) -> Result<bool, anyhow::Error> {
+        let Some(stamps) = self.credential_files.changed() else {
+            return Ok(false);
+        };
+
+        // Calls already holding clients finish on the old connection.
+        *self.clients.lock().await = None;
+        self.clients().await?;
+        self.credential_files.mark_loaded(stamps);
+
+        Ok(true)
+    }
 }
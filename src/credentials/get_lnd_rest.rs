@@ -0,0 +1,590 @@
+use std::{marker::PhantomData, sync::RwLock, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE},
+};
+use reqwest::{Certificate, Client, RequestBuilder, Response};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+use crate::{
+    config::Lnd,
+    credentials::{
+        get_cert::get_cert,
+        get_macaroon::get_macaroon,
+        lightning_backend::{
+            CreatedInvoice, InvoiceError, InvoiceRequest, InvoiceStatus, LightningBackend,
+            NodeInfo, PaidInvoice, PaymentFailed, SettledInvoice,
+        },
+        macaroon_audit::Permission,
+        reload::CredentialFiles,
+    },
+};
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long LND tries routes before giving up on a payment.
+const PAYMENT_TIMEOUT_SECS: i32 = 60;
+const PAYMENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// What LND answers when it refuses a payment before sending anything. Any other error leaves
+/// the payment's outcome unknown, since a proxy can time out while it is still in flight.
+const PAYMENT_REFUSALS: &[&str] = &[
+    "invoice is already paid",
+    "invoice expired",
+    "self-payments not allowed",
+    "amount must be specified",
+];
+
+#[derive(Deserialize)]
+struct AddInvoiceResponse {
+    r_hash: String,
+    payment_request: String,
+}
+
+#[derive(Deserialize)]
+struct RestInvoice {
+    #[serde(default)]
+    r_hash: String,
+    #[serde(default)]
+    payment_request: String,
+    #[serde(default)]
+    r_preimage: String,
+    #[serde(default)]
+    settle_date: String,
+    #[serde(default)]
+    settle_index: String,
+    #[serde(default)]
+    state: String,
+}
+
+impl RestInvoice {
+    fn into_settled(self) -> Result<SettledInvoice, anyhow::Error> {
+        Ok(SettledInvoice {
+            payment_hash: STANDARD.decode(self.r_hash)?,
+            payment_request: self.payment_request,
+            preimage: STANDARD.decode(self.r_preimage)?,
+            settle_date: self.settle_date.parse().unwrap_or_default(),
+            settle_index: self.settle_index.parse().unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RestPayment {
+    #[serde(default)]
+    payment_hash: String,
+    #[serde(default)]
+    payment_preimage: String,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    fee_msat: String,
+    #[serde(default)]
+    failure_reason: String,
+}
+
+/// Reads updates from a grpc-gateway server stream, which arrive as newline-delimited JSON
+/// objects that may span several chunks.
+struct GatewayStream<T> {
+    response: Response,
+    buffer: Vec<u8>,
+    update: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> GatewayStream<T> {
+    fn new(response: Response) -> Self {
+        GatewayStream {
+            response,
+            buffer: Vec::new(),
+            update: PhantomData,
+        }
+    }
+
+    /// The next line of the stream, or `None` once the stream ends.
+    async fn next_message(&mut self) -> Result<Option<StreamMessage<T>>, anyhow::Error> {
+        loop {
+            while let Some(position) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=position).collect();
+                if line.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+
+                return Ok(Some(serde_json::from_slice(&line)?));
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// The next update, or `None` once the stream ends.
+    async fn next(&mut self) -> Result<Option<T>, anyhow::Error> {
+        while let Some(message) = self.next_message().await? {
+            if let Some(error) = message.error {
+                return Err(anyhow!("LndRestSubscriptionError: {}", error.message));
+            }
+
+            if let Some(update) = message.result {
+                return Ok(Some(update));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Deserialize)]
+struct RestError {
+    message: String,
+}
+
+/// One line of a grpc-gateway server stream.
+#[derive(Deserialize)]
+struct StreamMessage<T> {
+    result: Option<T>,
+    error: Option<RestError>,
+}
+
+#[derive(Deserialize)]
+struct GetInfoResponse {
+    identity_pubkey: String,
+    alias: String,
+    version: String,
+    #[serde(default)]
+    block_height: u32,
+    #[serde(default)]
+    synced_to_chain: bool,
+    #[serde(default)]
+    num_active_channels: u32,
+}
+
+#[derive(Deserialize)]
+struct ChannelConstraints {
+    #[serde(default)]
+    chan_reserve_sat: String,
+}
+
+#[derive(Deserialize)]
+struct RestChannel {
+    #[serde(default)]
+    remote_balance: String,
+    remote_constraints: Option<ChannelConstraints>,
+}
+
+#[derive(Deserialize)]
+struct ListChannelsResponse {
+    #[serde(default)]
+    channels: Vec<RestChannel>,
+}
+
+#[derive(Deserialize)]
+struct CheckMacPermResponse {
+    #[serde(default)]
+    valid: bool,
+}
+
+#[derive(Deserialize)]
+struct BakeMacaroonResponse {
+    macaroon: String,
+}
+
+fn macaroon_permissions(permissions: &[Permission]) -> serde_json::Value {
+    permissions
+        .iter()
+        .map(|permission| json!({ "entity": permission.entity, "action": permission.action }))
+        .collect()
+}
+
+/// Treats connection failures and timeouts as the node being offline.
+fn request_error(error: reqwest::Error) -> anyhow::Error {
+    if error.is_connect() || error.is_timeout() {
+        InvoiceError::offline(error)
+    } else {
+        error.into()
+    }
+}
+
+/// A `PaymentFailed` only for LND's own refusals, which leave nothing in flight.
+fn payment_error(error: RestError) -> anyhow::Error {
+    if PAYMENT_REFUSALS
+        .iter()
+        .any(|refusal| error.message.contains(refusal))
+    {
+        PaymentFailed::with_cause(format!("LndRestPaymentRefused: {}", error.message))
+    } else {
+        anyhow!("LndRestPaymentOutcomeUnknown: {}", error.message)
+    }
+}
+
+/// A client trusting the configured cert, and the macaroon it authenticates with.
+#[derive(Clone)]
+struct RestCredentials {
+    client: Client,
+    macaroon: String,
+}
+
+fn load_credentials(lnd_config: &Lnd) -> Result<RestCredentials, anyhow::Error> {
+    // Without a cert configured the gateway is assumed to sit behind a proxy with a
+    // publicly trusted certificate.
+    let mut builder = Client::builder();
+    if lnd_config.cert_path.is_some() || lnd_config.cert_hex.is_some() {
+        let pem = hex::decode(get_cert(lnd_config)?)
+            .map_err(|e| anyhow!("FailedToDecodeTlsCert: {}", e))?;
+        let cert =
+            Certificate::from_pem(&pem).map_err(|e| anyhow!("FailedToParseTlsCert: {}", e))?;
+        builder = builder
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(cert);
+    }
+
+    Ok(RestCredentials {
+        client: builder
+            .build()
+            .map_err(|e| anyhow!("FailedToBuildLndRestClient: {}", e))?,
+        macaroon: get_macaroon(lnd_config)?,
+    })
+}
+
+/// LND over its REST gateway, for deployments where gRPC/HTTP2 is unavailable. The cert and
+/// macaroon are loaded on first use, so a node still writing them at startup is retried.
+pub struct LndRestBackend {
+    lnd_config: Lnd,
+    base_url: String,
+    credential_files: CredentialFiles,
+    credentials: RwLock<Option<RestCredentials>>,
+}
+
+impl LndRestBackend {
+    pub fn connect(lnd_config: &Lnd) -> Self {
+        let socket = lnd_config
+            .rest_socket
+            .clone()
+            .unwrap_or_else(|| lnd_config.socket.clone());
+
+        LndRestBackend {
+            lnd_config: lnd_config.clone(),
+            base_url: format!("https://{}", socket),
+            credential_files: CredentialFiles::new(lnd_config),
+            credentials: RwLock::new(None),
+        }
+    }
+
+    fn credentials(&self) -> Result<RestCredentials, anyhow::Error> {
+        if let Some(credentials) = self
+            .credentials
+            .read()
+            .expect("lnd rest credentials lock poisoned")
+            .as_ref()
+        {
+            return Ok(credentials.clone());
+        }
+
+        let credentials = load_credentials(&self.lnd_config).map_err(InvoiceError::offline)?;
+        info!(target: "credentials::get_lnd_rest", "Loaded the cert and macaroon for {}", self.base_url);
+        *self
+            .credentials
+            .write()
+            .expect("lnd rest credentials lock poisoned") = Some(credentials.clone());
+
+        Ok(credentials)
+    }
+
+    fn client(&self) -> Result<Client, anyhow::Error> {
+        Ok(self.credentials()?.client)
+    }
+
+    fn request(&self, builder: RequestBuilder) -> Result<RequestBuilder, anyhow::Error> {
+        Ok(builder.header("Grpc-Metadata-macaroon", self.credentials()?.macaroon))
+    }
+
+    async fn send<T: DeserializeOwned>(&self, builder: RequestBuilder) -> Result<T, anyhow::Error> {
+        let response = self
+            .request(builder.timeout(RPC_TIMEOUT))?
+            .send()
+            .await
+            .map_err(request_error)?;
+        let status = response.status();
+
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("LndRestRequestFailed: {} {}", status, body));
+        }
+
+        Ok(response.json::<T>().await?)
+    }
+
+    async fn subscribe(&self, url: String) -> Result<GatewayStream<RestInvoice>, anyhow::Error> {
+        let response = self
+            .request(self.client()?.get(url))?
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("LndRestSubscriptionFailed: {}", response.status()));
+        }
+
+        Ok(GatewayStream::new(response))
+    }
+}
+
+#[async_trait]
+impl LightningBackend for LndRestBackend {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error> {
+        let url = format!("{}/v1/invoices", self.base_url);
+        let result: AddInvoiceResponse = self
+            .send(self.client()?.post(url).json(&json!({
+                "description_hash": STANDARD.encode(&request.description_hash),
+                "expiry": request.expiry.to_string(),
+                "memo": request.memo,
+                "private": request.private,
+                "value_msat": request.amount_msat.to_string(),
+                "r_preimage": request.preimage.map(|preimage| STANDARD.encode(preimage)),
+                "is_blinded": request.blinded_paths.is_some(),
+                "blinded_path_config": request.blinded_paths.map(|paths| json!({
+                    "min_num_real_hops": paths.min_real_hops,
+                    "num_hops": paths.num_hops,
+                    "max_num_paths": paths.num_paths,
+                })),
+            })))
+            .await?;
+
+        Ok(CreatedInvoice {
+            payment_hash: STANDARD.decode(result.r_hash)?,
+            payment_request: result.payment_request,
+        })
+    }
+
+    fn supports_blinded_paths(&self) -> bool {
+        true
+    }
+
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error> {
+        let url = format!(
+            "{}/v2/invoices/subscribe/{}",
+            self.base_url,
+            URL_SAFE.encode(payment_hash)
+        );
+        let mut updates = self.subscribe(url).await?;
+        debug!(target: "credentials::get_lnd_rest", "Successfully subscribed to invoice updates");
+
+        while let Some(invoice) = updates.next().await? {
+            debug!(target: "credentials::get_lnd_rest", "Invoice state update: {}", invoice.state);
+
+            match invoice.state.as_str() {
+                "SETTLED" => return invoice.into_settled(),
+                "CANCELED" => return Err(anyhow!("InvoiceWasCanceled")),
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("InvoiceSubscriptionEnded"))
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error> {
+        let url = format!("{}/v1/invoice/{}", self.base_url, hex::encode(payment_hash));
+        let invoice: RestInvoice = self.send(self.client()?.get(url)).await?;
+        let settled = invoice.state == "SETTLED";
+
+        Ok(InvoiceStatus {
+            payment_request: invoice.payment_request,
+            settled,
+            preimage: if settled {
+                Some(STANDARD.decode(invoice.r_preimage)?)
+            } else {
+                None
+            },
+        })
+    }
+
+    fn supports_settlement_feed(&self) -> bool {
+        true
+    }
+
+    async fn subscribe_settlements(
+        &self,
+        settle_index: u64,
+        settlements: mpsc::Sender<SettledInvoice>,
+    ) -> Result<(), anyhow::Error> {
+        let url = format!(
+            "{}/v1/invoices/subscribe?settle_index={}",
+            self.base_url, settle_index
+        );
+        let mut updates = self.subscribe(url).await?;
+        debug!(target: "credentials::get_lnd_rest", "Subscribed to settlements after index {}", settle_index);
+
+        // The stream also reports newly added invoices, which are skipped.
+        while let Some(invoice) = updates.next().await? {
+            if invoice.state != "SETTLED" {
+                continue;
+            }
+
+            if settlements.send(invoice.into_settled()?).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+        let url = format!("{}/v1/invoices", self.base_url);
+        self.send::<AddInvoiceResponse>(self.client()?.post(url).json(&json!({
+            "value": "5",
+            "expiry": "100",
+        })))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+        let url = format!("{}/v1/getinfo", self.base_url);
+        let info: GetInfoResponse = self.send(self.client()?.get(url)).await?;
+
+        Ok(NodeInfo {
+            pubkey: info.identity_pubkey,
+            alias: info.alias,
+            version: info.version,
+            block_height: info.block_height,
+            synced_to_chain: info.synced_to_chain,
+            num_active_channels: Some(info.num_active_channels),
+        })
+    }
+
+    async fn get_inbound_capacity(&self) -> Result<Option<i64>, anyhow::Error> {
+        let url = format!("{}/v1/channels?active_only=true", self.base_url);
+        let response: ListChannelsResponse = self.send(self.client()?.get(url)).await?;
+
+        // The peer has to keep its channel reserve, so that part of its balance cannot be sent.
+        let inbound_sat: i64 = response
+            .channels
+            .iter()
+            .map(|channel| {
+                let remote_balance: i64 = channel.remote_balance.parse().unwrap_or_default();
+                let reserve_sat: i64 = channel
+                    .remote_constraints
+                    .as_ref()
+                    .and_then(|constraints| constraints.chan_reserve_sat.parse().ok())
+                    .unwrap_or_default();
+                (remote_balance - reserve_sat).max(0)
+            })
+            .sum();
+
+        Ok(Some(inbound_sat * 1000))
+    }
+
+    async fn pay_invoice(
+        &self,
+        payment_request: &str,
+        max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        let url = format!("{}/v2/router/send", self.base_url);
+        let builder = self.client()?.post(url).json(&json!({
+            "payment_request": payment_request,
+            "fee_limit_msat": max_fee_msat.to_string(),
+            "timeout_seconds": PAYMENT_TIMEOUT_SECS,
+            "no_inflight_updates": true,
+        }));
+        let response = self
+            .request(builder.timeout(PAYMENT_TIMEOUT))?
+            .send()
+            .await
+            .map_err(request_error)?;
+        let status = response.status();
+
+        // A body that is not LND's error, e.g. a proxy's 502, says nothing about the payment.
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let error = serde_json::from_str(&body).unwrap_or(RestError {
+                message: format!("{} {}", status, body),
+            });
+            return Err(payment_error(error));
+        }
+
+        let mut updates = GatewayStream::<RestPayment>::new(response);
+        while let Some(message) = updates.next_message().await? {
+            if let Some(error) = message.error {
+                return Err(payment_error(error));
+            }
+
+            let Some(payment) = message.result else {
+                continue;
+            };
+            match payment.status.as_str() {
+                "SUCCEEDED" => {
+                    return Ok(PaidInvoice {
+                        payment_hash: hex::decode(payment.payment_hash)?,
+                        preimage: hex::decode(payment.payment_preimage)?,
+                        fee_msat: payment.fee_msat.parse().unwrap_or_default(),
+                    });
+                }
+                "FAILED" => {
+                    return Err(PaymentFailed::with_cause(format!(
+                        "LndPaymentFailed: {}",
+                        payment.failure_reason
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("PaymentUpdatesEnded"))
+    }
+
+    async fn has_permissions(
+        &self,
+        permissions: &[Permission],
+    ) -> Result<Option<bool>, anyhow::Error> {
+        let url = format!("{}/v1/macaroon/checkpermissions", self.base_url);
+        let macaroon = STANDARD.encode(hex::decode(self.credentials()?.macaroon)?);
+        let response: CheckMacPermResponse = self
+            .send(self.client()?.post(url).json(&json!({
+                "macaroon": macaroon,
+                "permissions": macaroon_permissions(permissions),
+            })))
+            .await?;
+
+        Ok(Some(response.valid))
+    }
+
+    async fn bake_macaroon(&self, permissions: &[Permission]) -> Result<String, anyhow::Error> {
+        let url = format!("{}/v1/macaroon", self.base_url);
+        let response: BakeMacaroonResponse = self
+            .send(self.client()?.post(url).json(&json!({
+                "permissions": macaroon_permissions(permissions),
+            })))
+            .await?;
+
+        Ok(response.macaroon)
+    }
+
+    async fn reload_credentials(&self) -> Result<bool, anyhow::Error> {
+        let Some(stamps) = self.credential_files.changed() else {
+            return Ok(false);
+        };
+
+        let credentials = load_credentials(&self.lnd_config)?;
+        *self
+            .credentials
+            .write()
+            .expect("lnd rest credentials lock poisoned") = Some(credentials);
+        self.credential_files.mark_loaded(stamps);
+
+        Ok(true)
+    }
+}
@@ -1,29 +1,23 @@
-use crate::config::get_config;
+use crate::config::Lnd;
+use anyhow::anyhow;
 use std::fs;
 
-pub fn get_macaroon() -> String {
-    let config = get_config();
-    let lnd_config = &config.lnd;
-
-    let macaroon_path = &lnd_config.macaroon_path;
-    let macaroon_hex = &lnd_config.macaroon_hex;
-
-    if macaroon_path.is_none() && macaroon_hex.is_none() {
-        panic!("ExpectedEitherMacaroonPathOrMacaroonHexToAuthenticateToLnd");
-    }
-
-    if let Some(path) = macaroon_path {
-        if !path.is_empty() {
-            let mac_bytes = fs::read(path).expect("FailedToReadMacaroonFile");
-            return hex::encode(mac_bytes);
-        }
+pub fn get_macaroon(lnd_config: &Lnd) -> Result<String, anyhow::Error> {
+    if let Some(path) = &lnd_config.macaroon_path
+        && !path.is_empty()
+    {
+        let mac_bytes =
+            fs::read(path).map_err(|e| anyhow!("FailedToReadMacaroonFile: {}: {}", path, e))?;
+        return Ok(hex::encode(mac_bytes));
     }
 
-    if let Some(hex) = macaroon_hex {
-        if !hex.is_empty() {
-            return hex.to_string();
-        }
+    if let Some(hex) = &lnd_config.macaroon_hex
+        && !hex.is_empty()
+    {
+        return Ok(hex.to_string());
     }
 
-    panic!("ExpectedEitherMacaroonPathOrMacaroonHexToAuthenticateToLnd");
+    Err(anyhow!(
+        "ExpectedEitherMacaroonPathOrMacaroonHexToAuthenticateToLnd"
+    ))
 }
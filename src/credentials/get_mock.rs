@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bech32::{ToBase32, Variant, convert_bits, encode, u5};
+use once_cell::sync::OnceCell;
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Notify, mpsc};
+use tracing::info;
+
+use crate::credentials::lightning_backend::{
+    CreatedInvoice, InvoiceRequest, InvoiceStatus, LightningBackend, NodeInfo, PaidInvoice,
+    SettledInvoice,
+};
+
+static MOCK_BACKEND: OnceCell<Arc<MockBackend>> = OnceCell::new();
+
+// BOLT11 tagged field types.
+const TAG_PAYMENT_HASH: u8 = 1;
+const TAG_DESCRIPTION: u8 = 13;
+const TAG_PAYMENT_SECRET: u8 = 16;
+const TAG_DESCRIPTION_HASH: u8 = 23;
+const TAG_EXPIRY: u8 = 6;
+const TAG_FEATURES: u8 = 5;
+
+// var_onion_optin (8) and payment_secret (14), both required.
+const INVOICE_FEATURES: u64 = (1 << 8) | (1 << 14);
+
+struct MockInvoice {
+    payment_request: String,
+    preimage: [u8; 32],
+    /// Settle date and index once paid.
+    settled: Option<(i64, u64)>,
+}
+
+impl MockInvoice {
+    fn to_settled(&self, payment_hash: &[u8]) -> Option<SettledInvoice> {
+        self.settled
+            .map(|(settle_date, settle_index)| SettledInvoice {
+                payment_hash: payment_hash.to_vec(),
+                payment_request: self.payment_request.clone(),
+                preimage: self.preimage.to_vec(),
+                settle_date,
+                settle_index,
+            })
+    }
+}
+
+/// In-memory node for demos and tests. Invoices are valid regtest BOLT11 strings signed with
+/// a throwaway key and are only settled when `settle_invoice` is called.
+pub struct MockBackend {
+    node_key: SecretKey,
+    invoices: Mutex<HashMap<Vec<u8>, MockInvoice>>,
+    settle_count: AtomicU64,
+    settled: Notify,
+}
+
+/// The process-wide mock node, so invoices created by one request can be settled by another.
+pub fn get_mock() -> Arc<MockBackend> {
+    MOCK_BACKEND
+        .get_or_init(|| {
+            let backend = MockBackend::new();
+            info!(target: "credentials::get_mock", "Started mock node {}", backend.pubkey());
+            Arc::new(backend)
+        })
+        .clone()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Big-endian base32 encoding of `value` using the fewest 5-bit groups, or `width` when set.
+fn int_to_u5s(value: u64, width: Option<usize>) -> Vec<u5> {
+    let mut groups = Vec::new();
+    let mut remaining = value;
+
+    while remaining > 0 || groups.is_empty() || width.is_some_and(|w| groups.len() < w) {
+        groups.push(u5::try_from_u8((remaining & 31) as u8).expect("value masked to five bits"));
+        remaining >>= 5;
+    }
+
+    groups.reverse();
+    groups
+}
+
+fn tagged_field(tag: u8, data: Vec<u5>) -> Vec<u5> {
+    let mut field = vec![u5::try_from_u8(tag).expect("BOLT11 tags fit in five bits")];
+    field.extend(int_to_u5s(data.len() as u64, Some(2)));
+    field.extend(data);
+    field
+}
+
+impl MockBackend {
+    fn new() -> Self {
+        let node_key = loop {
+            if let Ok(key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+                break key;
+            }
+        };
+
+        MockBackend {
+            node_key,
+            invoices: Mutex::new(HashMap::new()),
+            settle_count: AtomicU64::new(0),
+            settled: Notify::new(),
+        }
+    }
+
+    fn pubkey(&self) -> String {
+        let secp = Secp256k1::new();
+        PublicKey::from_secret_key(&secp, &self.node_key).to_string()
+    }
+
+    fn encode_invoice(
+        &self,
+        request: &InvoiceRequest,
+        payment_hash: &[u8],
+    ) -> Result<String, anyhow::Error> {
+        let hrp = if request.amount_msat > 0 {
+            // One millisatoshi is ten pico-bitcoin.
+            format!("lnbcrt{}p", request.amount_msat * 10)
+        } else {
+            "lnbcrt".to_string()
+        };
+
+        let mut data = int_to_u5s(now() as u64, Some(7));
+        data.extend(tagged_field(TAG_PAYMENT_HASH, payment_hash.to_base32()));
+        data.extend(tagged_field(
+            TAG_PAYMENT_SECRET,
+            rand::random::<[u8; 32]>().to_base32(),
+        ));
+        if request.description_hash.is_empty() {
+            data.extend(tagged_field(
+                TAG_DESCRIPTION,
+                request.memo.as_bytes().to_base32(),
+            ));
+        } else {
+            data.extend(tagged_field(
+                TAG_DESCRIPTION_HASH,
+                request.description_hash.to_base32(),
+            ));
+        }
+        data.extend(tagged_field(
+            TAG_EXPIRY,
+            int_to_u5s(request.expiry as u64, None),
+        ));
+        data.extend(tagged_field(
+            TAG_FEATURES,
+            int_to_u5s(INVOICE_FEATURES, None),
+        ));
+
+        // The signature commits to the hrp bytes followed by the data part zero-padded to bytes.
+        let mut preimage = hrp.as_bytes().to_vec();
+        preimage.extend(convert_bits(&data, 5, 8, true)?);
+        let message = Message::from_slice(&Sha256::digest(&preimage))?;
+
+        let secp = Secp256k1::new();
+        let (recovery_id, signature) = secp
+            .sign_ecdsa_recoverable(&message, &self.node_key)
+            .serialize_compact();
+
+        let mut signature = signature.to_vec();
+        signature.push(recovery_id.to_i32() as u8);
+        data.extend(signature.to_base32());
+
+        Ok(encode(&hrp, data, Variant::Bech32)?)
+    }
+
+    /// Marks an invoice as paid and wakes anything waiting on it.
+    pub fn settle_invoice(&self, payment_hash: &[u8]) -> Result<SettledInvoice, anyhow::Error> {
+        let mut invoices = self.invoices.lock().expect("mock invoice lock poisoned");
+        let invoice = invoices
+            .get_mut(payment_hash)
+            .ok_or_else(|| anyhow!("InvoiceNotFound"))?;
+
+        if invoice.settled.is_none() {
+            let settle_index = self.settle_count.fetch_add(1, Ordering::Relaxed) + 1;
+            invoice.settled = Some((now(), settle_index));
+        }
+        self.settled.notify_waiters();
+
+        Ok(invoice
+            .to_settled(payment_hash)
+            .expect("invoice was just settled"))
+    }
+
+    fn settled_invoice(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<Option<SettledInvoice>, anyhow::Error> {
+        let invoices = self.invoices.lock().expect("mock invoice lock poisoned");
+        let invoice = invoices
+            .get(payment_hash)
+            .ok_or_else(|| anyhow!("InvoiceNotFound"))?;
+
+        Ok(invoice.to_settled(payment_hash))
+    }
+
+    /// Invoices settled after `settle_index`, oldest first.
+    fn settled_since(&self, settle_index: u64) -> Vec<SettledInvoice> {
+        let invoices = self.invoices.lock().expect("mock invoice lock poisoned");
+        let mut settled: Vec<SettledInvoice> = invoices
+            .iter()
+            .filter_map(|(payment_hash, invoice)| invoice.to_settled(payment_hash))
+            .filter(|invoice| invoice.settle_index > settle_index)
+            .collect();
+
+        settled.sort_by_key(|invoice| invoice.settle_index);
+        settled
+    }
+}
+
+#[async_trait]
+impl LightningBackend for MockBackend {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error> {
+        let preimage: [u8; 32] = request.preimage.unwrap_or_else(rand::random);
+        let payment_hash = Sha256::digest(preimage).to_vec();
+        let payment_request = self.encode_invoice(&request, &payment_hash)?;
+
+        self.invoices
+            .lock()
+            .expect("mock invoice lock poisoned")
+            .insert(
+                payment_hash.clone(),
+                MockInvoice {
+                    payment_request: payment_request.clone(),
+                    preimage,
+                    settled: None,
+                },
+            );
+
+        Ok(CreatedInvoice {
+            payment_hash,
+            payment_request,
+        })
+    }
+
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error> {
+        loop {
+            // Register for the wake-up before checking, so a settle in between is not missed.
+            let notified = self.settled.notified();
+
+            if let Some(settled) = self.settled_invoice(payment_hash)? {
+                return Ok(settled);
+            }
+
+            notified.await;
+        }
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error> {
+        let invoices = self.invoices.lock().expect("mock invoice lock poisoned");
+        let invoice = invoices
+            .get(payment_hash)
+            .ok_or_else(|| anyhow!("InvoiceNotFound"))?;
+
+        Ok(InvoiceStatus {
+            payment_request: invoice.payment_request.clone(),
+            settled: invoice.settled.is_some(),
+            preimage: invoice.settled.map(|_| invoice.preimage.to_vec()),
+        })
+    }
+
+    fn supports_settlement_feed(&self) -> bool {
+        true
+    }
+
+    async fn subscribe_settlements(
+        &self,
+        settle_index: u64,
+        settlements: mpsc::Sender<SettledInvoice>,
+    ) -> Result<(), anyhow::Error> {
+        let mut settle_index = settle_index;
+
+        loop {
+            let notified = self.settled.notified();
+
+            for invoice in self.settled_since(settle_index) {
+                settle_index = invoice.settle_index;
+                if settlements.send(invoice).await.is_err() {
+                    return Ok(());
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Settles the invoice when the mock node issued it. Any other invoice is taken as paid
+    /// with a made-up preimage.
+    async fn pay_invoice(
+        &self,
+        payment_request: &str,
+        _max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        let own_invoice = self
+            .invoices
+            .lock()
+            .expect("mock invoice lock poisoned")
+            .iter()
+            .find(|(_, invoice)| invoice.payment_request == payment_request)
+            .map(|(payment_hash, _)| payment_hash.clone());
+
+        if let Some(payment_hash) = own_invoice {
+            let settled = self.settle_invoice(&payment_hash)?;
+            return Ok(PaidInvoice {
+                payment_hash,
+                preimage: settled.preimage,
+                fee_msat: 0,
+            });
+        }
+
+        let preimage: [u8; 32] = rand::random();
+        info!(target: "credentials::get_mock", "Pretending to pay {}", payment_request);
+
+        Ok(PaidInvoice {
+            payment_hash: Sha256::digest(preimage).to_vec(),
+            preimage: preimage.to_vec(),
+            fee_msat: 0,
+        })
+    }
+
+    async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+        self.create_invoice(InvoiceRequest {
+            description: String::new(),
+            description_hash: vec![],
+            memo: "rustdress test invoice".to_string(),
+            amount_msat: 5000,
+            expiry: 100,
+            private: false,
+            blinded_paths: None,
+            preimage: None,
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+        Ok(NodeInfo {
+            pubkey: self.pubkey(),
+            alias: "rustdress-mock".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            block_height: 0,
+            synced_to_chain: true,
+            num_active_channels: Some(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bech32::decode;
+    use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    #[test]
+    fn encodes_bolt11_invoice() {
+        let backend = MockBackend::new();
+        let description_hash = Sha256::digest(b"[[\"text/plain\",\"hello\"]]").to_vec();
+        let payment_hash = Sha256::digest([7u8; 32]).to_vec();
+
+        let invoice = backend
+            .encode_invoice(
+                &InvoiceRequest {
+                    description: String::new(),
+                    description_hash: description_hash.clone(),
+                    memo: String::new(),
+                    amount_msat: 21_000,
+                    expiry: 600,
+                    private: false,
+                    blinded_paths: None,
+                    preimage: None,
+                },
+                &payment_hash,
+            )
+            .unwrap();
+
+        let (hrp, data, variant) = decode(&invoice).unwrap();
+        assert_eq!(hrp, "lnbcrt210000p");
+        assert_eq!(variant, Variant::Bech32);
+
+        // A timestamp, then tagged fields, then a 65-byte signature.
+        let (fields, signature) = data.split_at(data.len() - 104);
+        let mut tags = HashMap::new();
+        let mut rest = &fields[7..];
+        while !rest.is_empty() {
+            let len = (rest[1].to_u8() as usize) << 5 | rest[2].to_u8() as usize;
+            tags.insert(rest[0].to_u8(), &rest[3..3 + len]);
+            rest = &rest[3 + len..];
+        }
+        let field_bytes = |tag| convert_bits(tags[&tag], 5, 8, false).unwrap();
+        assert_eq!(field_bytes(TAG_PAYMENT_HASH), payment_hash);
+        assert_eq!(field_bytes(TAG_DESCRIPTION_HASH), description_hash);
+        assert!(!tags.contains_key(&TAG_DESCRIPTION));
+
+        let mut preimage = hrp.as_bytes().to_vec();
+        preimage.extend(convert_bits(fields, 5, 8, true).unwrap());
+        let message = Message::from_slice(&Sha256::digest(&preimage)).unwrap();
+        let signature = convert_bits(signature, 5, 8, false).unwrap();
+        let signature = RecoverableSignature::from_compact(
+            &signature[..64],
+            RecoveryId::from_i32(signature[64] as i32).unwrap(),
+        )
+        .unwrap();
+        let signer = Secp256k1::new()
+            .recover_ecdsa(&message, &signature)
+            .unwrap();
+        assert_eq!(signer.to_string(), backend.pubkey());
+    }
+
+    #[tokio::test]
+    async fn wakes_waiters_in_settle_index_order() {
+        let backend = Arc::new(MockBackend::new());
+        let mut payment_hashes = vec![];
+        for memo in ["first", "second"] {
+            let invoice = backend
+                .create_invoice(InvoiceRequest {
+                    description: String::new(),
+                    description_hash: vec![],
+                    memo: memo.to_string(),
+                    amount_msat: 1000,
+                    expiry: 600,
+                    private: false,
+                    blinded_paths: None,
+                    preimage: None,
+                })
+                .await
+                .unwrap();
+            payment_hashes.push(invoice.payment_hash);
+        }
+
+        let (sender, mut settlements) = mpsc::channel(4);
+        let feed = backend.clone();
+        tokio::spawn(async move { feed.subscribe_settlements(0, sender).await });
+        let waiter = backend.clone();
+        let second_hash = payment_hashes[1].clone();
+        let waiting = tokio::spawn(async move { waiter.wait_for_settlement(&second_hash).await });
+        // Let both start waiting before anything is settled.
+        sleep(Duration::from_millis(50)).await;
+
+        backend.settle_invoice(&payment_hashes[1]).unwrap();
+        backend.settle_invoice(&payment_hashes[0]).unwrap();
+
+        let settled = timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(settled.payment_hash, payment_hashes[1]);
+        assert_eq!(settled.settle_index, 1);
+
+        for (payment_hash, settle_index) in [(&payment_hashes[1], 1), (&payment_hashes[0], 2)] {
+            let settled = timeout(Duration::from_secs(5), settlements.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&settled.payment_hash, payment_hash);
+            assert_eq!(settled.settle_index, settle_index);
+        }
+    }
+}
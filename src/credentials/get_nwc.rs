@@ -0,0 +1,553 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rusted_nostr_tools::event_methods::{
+    SignedEvent, UnsignedEvent, get_event_hash, verify_signature,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+use urlencoding::decode;
+
+use crate::{
+    config::Nwc,
+    credentials::lightning_backend::{
+        CreatedInvoice, InvoiceError, InvoiceRequest, InvoiceStatus, LightningBackend, NodeInfo,
+        PaidInvoice, PaymentFailed, SettledInvoice,
+    },
+    server::{
+        encryption::{nip04_decrypt, nip04_encrypt},
+        publish_to_relay::send_and_wait_for_event,
+        utils::sign_nostr_event,
+    },
+};
+
+const NWC_REQUEST_KIND: u64 = 23194;
+const NWC_RESPONSE_KIND: u64 = 23195;
+const NWC_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const NWC_LOOKUP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// NIP-47 error codes meaning the wallet service did not make a payment.
+const NWC_PAYMENT_NOT_MADE: &[&str] = &[
+    "PAYMENT_FAILED",
+    "INSUFFICIENT_BALANCE",
+    "QUOTA_EXCEEDED",
+    "RATE_LIMITED",
+    "RESTRICTED",
+    "UNAUTHORIZED",
+    "NOT_IMPLEMENTED",
+];
+
+#[derive(Deserialize, Debug)]
+struct NwcError {
+    code: String,
+    message: String,
+}
+
+impl std::fmt::Display for NwcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for NwcError {}
+
+#[derive(Deserialize)]
+struct NwcResponse {
+    result_type: String,
+    error: Option<NwcError>,
+    result: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct NwcTransaction {
+    invoice: Option<String>,
+    payment_hash: String,
+    preimage: Option<String>,
+    settled_at: Option<i64>,
+    expires_at: Option<i64>,
+}
+
+impl NwcTransaction {
+    fn into_status(self) -> Result<InvoiceStatus, anyhow::Error> {
+        let settled = self.settled_at.is_some();
+
+        Ok(InvoiceStatus {
+            payment_request: self.invoice.unwrap_or_default(),
+            settled,
+            preimage: match self.preimage {
+                Some(preimage) if settled => Some(hex::decode(preimage)?),
+                _ => None,
+            },
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct NwcPayment {
+    preimage: String,
+    #[serde(default)]
+    fees_paid: i64,
+}
+
+#[derive(Deserialize)]
+struct NwcInfo {
+    #[serde(default)]
+    alias: String,
+    #[serde(default)]
+    pubkey: String,
+    #[serde(default)]
+    block_height: u32,
+}
+
+fn is_signed_by(event: &SignedEvent, pubkey: &str) -> bool {
+    let unsigned = UnsignedEvent {
+        content: event.content.clone(),
+        created_at: event.created_at,
+        kind: event.kind,
+        tags: event.tags.clone(),
+        pubkey: event.pubkey.clone(),
+    };
+
+    // verify_signature panics on malformed hex, so the id and signature shapes are checked
+    // before it is called.
+    event.pubkey == pubkey
+        && get_event_hash(&unsigned).is_ok_and(|id| id == event.id)
+        && hex::decode(&event.sig).is_ok_and(|sig| sig.len() == 64)
+        && verify_signature(&event.sig, &event.pubkey, &event.id).is_ok()
+}
+
+/// A wallet service reached through a NIP-47 (Nostr Wallet Connect) connection string.
+pub struct NwcBackend {
+    wallet_pubkey: String,
+    relay: String,
+    secret: String,
+}
+
+impl NwcBackend {
+    pub fn connect(nwc_config: &Nwc) -> Self {
+        Self::from_connection_string(&nwc_config.connection_string)
+            .expect("FailedToParseNwcConnectionString")
+    }
+
+    /// Parses `nostr+walletconnect://<wallet pubkey>?relay=<url>&secret=<hex>`.
+    pub fn from_connection_string(connection_string: &str) -> Result<Self, anyhow::Error> {
+        let rest = connection_string
+            .strip_prefix("nostr+walletconnect://")
+            .ok_or_else(|| anyhow!("ExpectedNostrWalletConnectScheme"))?;
+        let (wallet_pubkey, query) = rest
+            .split_once('?')
+            .ok_or_else(|| anyhow!("MissingNwcConnectionParameters"))?;
+
+        let mut relay = None;
+        let mut secret = None;
+        for kv in query.split('&') {
+            let mut iter = kv.split('=');
+            let key = iter.next().unwrap_or_default();
+            let value = decode(iter.next().unwrap_or_default())?.into_owned();
+            match key {
+                "relay" if relay.is_none() => relay = Some(value),
+                "secret" => secret = Some(value),
+                _ => {}
+            }
+        }
+
+        let secret = secret.ok_or_else(|| anyhow!("MissingNwcSecret"))?;
+        secp256k1::SecretKey::from_slice(&hex::decode(&secret)?)?;
+
+        Ok(NwcBackend {
+            wallet_pubkey: wallet_pubkey.trim_end_matches('/').to_string(),
+            relay: relay.ok_or_else(|| anyhow!("MissingNwcRelay"))?,
+            secret,
+        })
+    }
+
+    /// Sends one NIP-47 request and waits for the wallet service's response.
+    async fn call(&self, method: &str, params: Value) -> Result<Value, anyhow::Error> {
+        let content = json!({ "method": method, "params": params }).to_string();
+        let encrypted =
+            nip04_encrypt(&self.secret, &self.wallet_pubkey, &content).map_err(|e| anyhow!(e))?;
+
+        let request = sign_nostr_event(
+            &self.secret,
+            NWC_REQUEST_KIND,
+            vec![vec!["p".to_string(), self.wallet_pubkey.clone()]],
+            encrypted,
+        )
+        .map_err(|e| anyhow!(e))?;
+        debug!(target: "credentials::get_nwc", "Sending {} request {}", method, request.id);
+
+        let filter = json!({
+            "kinds": [NWC_RESPONSE_KIND],
+            "authors": [self.wallet_pubkey],
+            "#e": [request.id],
+        });
+        let message = json!(["EVENT", request]).to_string();
+
+        let response = timeout(
+            NWC_RESPONSE_TIMEOUT,
+            send_and_wait_for_event(&self.relay, filter, message),
+        )
+        .await
+        .map_err(|_| InvoiceError::offline("NwcResponseTimedOut"))?
+        .map_err(InvoiceError::offline)?;
+
+        self.read_response(method, &response)
+    }
+
+    /// Checks that a response came from the wallet service and extracts its result.
+    fn read_response(&self, method: &str, response: &SignedEvent) -> Result<Value, anyhow::Error> {
+        if !is_signed_by(response, &self.wallet_pubkey) {
+            return Err(anyhow!("InvalidNwcResponseSignature"));
+        }
+
+        let decrypted = nip04_decrypt(&self.secret, &self.wallet_pubkey, &response.content)
+            .map_err(|e| anyhow!(e))?;
+        let response: NwcResponse = serde_json::from_str(&decrypted)?;
+
+        if let Some(error) = response.error {
+            let message = format!("Nwc{}Failed: {}", method, error);
+            return Err(anyhow::Error::new(error).context(message));
+        }
+
+        if response.result_type != method {
+            return Err(anyhow!("UnexpectedNwcResultType: {}", response.result_type));
+        }
+
+        response
+            .result
+            .ok_or_else(|| anyhow!("NwcResponseMissingResultFor{}", method))
+    }
+}
+
+#[async_trait]
+impl LightningBackend for NwcBackend {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error> {
+        // NIP-47 `make_invoice` has no way to pass a preimage.
+        if request.preimage.is_some() {
+            return Err(anyhow!("NwcCannotCreateInvoiceWithPreimage"));
+        }
+
+        let result = self
+            .call(
+                "make_invoice",
+                json!({
+                    "amount": request.amount_msat,
+                    "description": request.description,
+                    "description_hash": hex::encode(&request.description_hash),
+                    "expiry": request.expiry,
+                }),
+            )
+            .await?;
+        let transaction: NwcTransaction = serde_json::from_value(result)?;
+
+        Ok(CreatedInvoice {
+            payment_hash: hex::decode(transaction.payment_hash)?,
+            payment_request: transaction
+                .invoice
+                .ok_or_else(|| anyhow!("NwcResponseMissingInvoice"))?,
+        })
+    }
+
+    /// NIP-47 notifications are optional for wallet services, so settlement is detected by
+    /// polling `lookup_invoice` until the invoice is settled or expires.
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error> {
+        let payment_hash = hex::encode(payment_hash);
+
+        loop {
+            let result = self
+                .call("lookup_invoice", json!({ "payment_hash": payment_hash }))
+                .await;
+
+            match result.and_then(|r| Ok(serde_json::from_value::<NwcTransaction>(r)?)) {
+                Ok(transaction) => {
+                    if let Some(settle_date) = transaction.settled_at {
+                        return Ok(SettledInvoice {
+                            payment_hash: hex::decode(transaction.payment_hash)?,
+                            payment_request: transaction.invoice.unwrap_or_default(),
+                            preimage: hex::decode(transaction.preimage.unwrap_or_default())?,
+                            settle_date,
+                            settle_index: 0,
+                        });
+                    }
+
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs() as i64;
+                    if transaction
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at < now)
+                    {
+                        return Err(anyhow!("InvoiceExpired"));
+                    }
+                }
+                Err(e) => {
+                    debug!(target: "credentials::get_nwc", "Failed to look up invoice, retrying: {}", e);
+                }
+            }
+
+            sleep(NWC_LOOKUP_INTERVAL).await;
+        }
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error> {
+        let result = self
+            .call(
+                "lookup_invoice",
+                json!({ "payment_hash": hex::encode(payment_hash) }),
+            )
+            .await?;
+        let transaction: NwcTransaction = serde_json::from_value(result)?;
+
+        transaction.into_status()
+    }
+
+    fn supports_chosen_preimage(&self) -> bool {
+        false
+    }
+
+    async fn test_invoice(&self) -> Result<(), anyhow::Error> {
+        self.call(
+            "make_invoice",
+            json!({
+                "amount": 5000,
+                "description": "rustdress test invoice",
+                "expiry": 100,
+            }),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// NIP-47 has no fee limit, so the wallet service's own applies.
+    async fn pay_invoice(
+        &self,
+        payment_request: &str,
+        _max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        let result = match self
+            .call("pay_invoice", json!({ "invoice": payment_request }))
+            .await
+        {
+            Ok(result) => result,
+            Err(e)
+                if e.downcast_ref::<NwcError>()
+                    .is_some_and(|error| NWC_PAYMENT_NOT_MADE.contains(&error.code.as_str())) =>
+            {
+                return Err(PaymentFailed::with_cause(e));
+            }
+            Err(e) => return Err(e),
+        };
+        let payment: NwcPayment = serde_json::from_value(result)?;
+        let preimage = hex::decode(payment.preimage)?;
+
+        Ok(PaidInvoice {
+            payment_hash: Sha256::digest(&preimage).to_vec(),
+            preimage,
+            fee_msat: payment.fees_paid,
+        })
+    }
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error> {
+        let info: NwcInfo = serde_json::from_value(self.call("get_info", json!({})).await?)?;
+
+        // Wallet services report neither chain sync nor channels; a reachable wallet is
+        // treated as synced and its channels are left unknown.
+        Ok(NodeInfo {
+            pubkey: if info.pubkey.is_empty() {
+                self.wallet_pubkey.clone()
+            } else {
+                info.pubkey
+            },
+            alias: info.alias,
+            version: "nip-47".to_string(),
+            block_height: info.block_height,
+            synced_to_chain: true,
+            num_active_channels: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusted_nostr_tools::GeneratePublicKey;
+
+    use super::*;
+
+    const WALLET_SECRET: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const CLIENT_SECRET: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+
+    fn wallet() -> NwcBackend {
+        NwcBackend::from_connection_string(&format!(
+            "nostr+walletconnect://{}?relay=wss%3A%2F%2Frelay.example.com&secret={}",
+            GeneratePublicKey::new(WALLET_SECRET).hex_public_key(),
+            CLIENT_SECRET
+        ))
+        .unwrap()
+    }
+
+    /// A wallet service response to `backend`, encrypted to it and signed with `signer`.
+    fn response(backend: &NwcBackend, signer: &str, content: Value) -> SignedEvent {
+        let client_pubkey = GeneratePublicKey::new(&backend.secret)
+            .hex_public_key()
+            .to_string();
+        let encrypted = nip04_encrypt(WALLET_SECRET, &client_pubkey, &content.to_string()).unwrap();
+
+        sign_nostr_event(
+            signer,
+            NWC_RESPONSE_KIND,
+            vec![vec!["p".to_string(), client_pubkey]],
+            encrypted,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_connection_string() {
+        let backend = wallet();
+
+        assert_eq!(
+            backend.wallet_pubkey,
+            GeneratePublicKey::new(WALLET_SECRET).hex_public_key()
+        );
+        assert_eq!(backend.relay, "wss://relay.example.com");
+        assert_eq!(backend.secret, CLIENT_SECRET);
+    }
+
+    #[test]
+    fn rejects_malformed_connection_strings() {
+        let pubkey = GeneratePublicKey::new(WALLET_SECRET)
+            .hex_public_key()
+            .to_string();
+
+        for connection_string in [
+            format!(
+                "nostrwalletconnect://{pubkey}?relay=wss://r.example.com&secret={CLIENT_SECRET}"
+            ),
+            format!("nostr+walletconnect://{pubkey}"),
+            format!("nostr+walletconnect://{pubkey}?secret={CLIENT_SECRET}"),
+            format!("nostr+walletconnect://{pubkey}?relay=wss://r.example.com"),
+            format!("nostr+walletconnect://{pubkey}?relay=wss://r.example.com&secret=zz"),
+            format!(
+                "nostr+walletconnect://{pubkey}?relay=wss://r.example.com&secret={}",
+                "00".repeat(32)
+            ),
+        ] {
+            assert!(
+                NwcBackend::from_connection_string(&connection_string).is_err(),
+                "{connection_string}"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_responses_signed_by_wallet() {
+        let backend = wallet();
+        let event = response(
+            &backend,
+            WALLET_SECRET,
+            json!({ "result_type": "get_info", "result": { "alias": "wallet" } }),
+        );
+
+        let result = backend.read_response("get_info", &event).unwrap();
+
+        assert_eq!(result["alias"], "wallet");
+    }
+
+    #[test]
+    fn rejects_responses_from_other_authors() {
+        let backend = wallet();
+        let content = json!({ "result_type": "get_info", "result": {} });
+
+        let other_author = response(&backend, CLIENT_SECRET, content.clone());
+        let err = backend
+            .read_response("get_info", &other_author)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "InvalidNwcResponseSignature");
+
+        // Claims the wallet as its author but is signed by another key.
+        let mut forged = response(&backend, CLIENT_SECRET, content);
+        forged.pubkey = backend.wallet_pubkey.clone();
+        let err = backend.read_response("get_info", &forged).unwrap_err();
+        assert_eq!(err.to_string(), "InvalidNwcResponseSignature");
+    }
+
+    #[test]
+    fn rejects_tampered_responses() {
+        let backend = wallet();
+        let mut event = response(
+            &backend,
+            WALLET_SECRET,
+            json!({ "result_type": "get_info", "result": {} }),
+        );
+        event.created_at += 1;
+
+        let err = backend.read_response("get_info", &event).unwrap_err();
+
+        assert_eq!(err.to_string(), "InvalidNwcResponseSignature");
+    }
+
+    #[test]
+    fn surfaces_wallet_errors() {
+        let backend = wallet();
+        let event = response(
+            &backend,
+            WALLET_SECRET,
+            json!({
+                "result_type": "pay_invoice",
+                "error": { "code": "INSUFFICIENT_BALANCE", "message": "Not enough funds" },
+            }),
+        );
+
+        let err = backend.read_response("pay_invoice", &event).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<NwcError>().map(|e| e.code.as_str()),
+            Some("INSUFFICIENT_BALANCE")
+        );
+    }
+
+    #[test]
+    fn maps_settled_transaction_to_status() {
+        let transaction: NwcTransaction = serde_json::from_value(json!({
+            "invoice": "lnbc1",
+            "payment_hash": "aa",
+            "preimage": "bb",
+            "settled_at": 1700000000,
+        }))
+        .unwrap();
+
+        let status = transaction.into_status().unwrap();
+
+        assert!(status.settled);
+        assert_eq!(status.payment_request, "lnbc1");
+        assert_eq!(status.preimage, Some(vec![0xbb]));
+    }
+
+    #[test]
+    fn withholds_preimage_of_unsettled_transaction() {
+        let transaction: NwcTransaction = serde_json::from_value(json!({
+            "invoice": "lnbc1",
+            "payment_hash": "aa",
+            "preimage": "bb",
+            "expires_at": 1700000000,
+        }))
+        .unwrap();
+
+        let status = transaction.into_status().unwrap();
+
+        assert!(!status.settled);
+        assert_eq!(status.preimage, None);
+    }
+}
@@ -1,6 +1,5 @@
-use crate::config::get_config;
+use crate::config::Lnd;
 
-pub fn get_socket() -> String {
-    let config = get_config();
-    config.lnd.socket.clone()
+pub fn get_socket(lnd_config: &Lnd) -> String {
+    lnd_config.socket.clone()
 }
@@ -0,0 +1,624 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{BackendKind, BlindedPaths, Cln, Lnd, Nwc, get_config, is_demo_mode},
+    credentials::{
+        failover::{FailoverBackend, FailoverNode},
+        get_cln::ClnBackend,
+        get_lnd::LndBackend,
+        get_lnd_rest::LndRestBackend,
+        get_mock::get_mock,
+        get_nwc::NwcBackend,
+        macaroon_audit::{Permission, audit_macaroon},
+    },
+};
+
+const DEFAULT_HEALTH_CHECK_INTERVAL_SECS: u64 = 60;
+const DEFAULT_STARTUP_RETRY_INITIAL_SECS: u64 = 1;
+const DEFAULT_STARTUP_RETRY_MAX_SECS: u64 = 60;
+const STARTUP_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Backends that passed their invoice test since startup, by `[[backends]]` name, `None`
+/// being the default one.
+static READY_BACKENDS: Lazy<Mutex<HashSet<Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Backends by `[[backends]]` name, `None` being the default one. Connections are reused across
+/// requests instead of being set up per invoice.
+static BACKENDS: Lazy<Mutex<HashMap<Option<String>, SharedBackend>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+type SharedBackend = Arc<dyn LightningBackend>;
+
+/// A node's name, `None` for a backend's only node, and whether its credentials reloaded.
+pub type NodeReload = (Option<String>, Result<bool, anyhow::Error>);
+
+/// Held while a backend is made, so concurrent first uses make it only once.
+static CREATING_BACKEND: Mutex<()> = Mutex::new(());
+
+/// Parameters for a new invoice, independent of the node software issuing it.
+#[derive(Debug, Clone)]
+pub struct InvoiceRequest {
+    /// Text whose SHA256 is `description_hash`, for nodes that hash the description themselves.
+    pub description: String,
+    pub description_hash: Vec<u8>,
+    pub memo: String,
+    pub amount_msat: i64,
+    pub expiry: i64,
+    pub private: bool,
+    /// Blinded paths to add instead of route hints, on nodes that support them.
+    pub blinded_paths: Option<BlindedPaths>,
+    /// Preimage chosen by rustdress, for success actions encrypted with it. The node picks one
+    /// when unset.
+    pub preimage: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CreatedInvoice {
+    pub payment_hash: Vec<u8>,
+    pub payment_request: String,
+}
+
+/// An invoice as the node reports it now, paid or not.
+#[derive(Debug, Clone)]
+pub struct InvoiceStatus {
+    pub payment_request: String,
+    pub settled: bool,
+    /// Only revealed once the invoice is settled.
+    pub preimage: Option<Vec<u8>>,
+}
+
+/// A payment the node made.
+#[derive(Debug, Clone)]
+pub struct PaidInvoice {
+    pub payment_hash: Vec<u8>,
+    pub preimage: Vec<u8>,
+    pub fee_msat: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SettledInvoice {
+    pub payment_hash: Vec<u8>,
+    pub payment_request: String,
+    pub preimage: Vec<u8>,
+    pub settle_date: i64,
+    /// Position in the node's settlement order, or 0 for nodes that do not number them.
+    pub settle_index: u64,
+}
+
+/// Why an invoice could not be created. Backends attach it to the errors they return when they
+/// can tell the cause, and the LNURL response reports it to the payer.
+#[derive(Debug)]
+pub enum InvoiceError {
+    /// The node could not be reached or did not answer in time.
+    NodeOffline,
+    /// The node's channels cannot receive the amount.
+    InsufficientInboundLiquidity,
+    /// The node refused the invoice for any other reason.
+    Rejected(String),
+}
+
+impl InvoiceError {
+    /// A backend error for a node that could not be reached, with `cause` as its message.
+    pub fn offline<C>(cause: C) -> anyhow::Error
+    where
+        C: std::fmt::Display + Send + Sync + 'static,
+    {
+        anyhow::Error::new(InvoiceError::NodeOffline).context(cause)
+    }
+
+    /// Finds the cause attached to a backend error, treating unknown errors as rejections.
+    pub fn from_backend(error: anyhow::Error) -> Self {
+        match error.downcast_ref::<InvoiceError>() {
+            Some(InvoiceError::NodeOffline) => InvoiceError::NodeOffline,
+            Some(InvoiceError::InsufficientInboundLiquidity) => {
+                InvoiceError::InsufficientInboundLiquidity
+            }
+            _ => InvoiceError::Rejected(error.to_string()),
+        }
+    }
+
+    /// LUD-06 error reason shown to the payer.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            InvoiceError::NodeOffline => "Node offline",
+            InvoiceError::InsufficientInboundLiquidity => "Insufficient inbound liquidity",
+            InvoiceError::Rejected(_) => "Invoice rejected",
+        }
+    }
+}
+
+impl std::fmt::Display for InvoiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvoiceError::Rejected(message) => write!(f, "InvoiceRejected: {}", message),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceError {}
+
+/// Attached to payment errors when the node gave up without anything left in flight, so the
+/// payment can safely be tried again. Other payment errors may still complete.
+#[derive(Debug)]
+pub struct PaymentFailed;
+
+impl PaymentFailed {
+    /// A backend error for a payment the node gave up on, with `cause` as its message.
+    pub fn with_cause<C>(cause: C) -> anyhow::Error
+    where
+        C: std::fmt::Display + Send + Sync + 'static,
+    {
+        anyhow::Error::new(PaymentFailed).context(cause)
+    }
+
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<PaymentFailed>().is_some()
+    }
+}
+
+impl std::fmt::Display for PaymentFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PaymentFailed")
+    }
+}
+
+impl std::error::Error for PaymentFailed {}
+
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub pubkey: String,
+    pub alias: String,
+    pub version: String,
+    pub block_height: u32,
+    pub synced_to_chain: bool,
+    /// `None` when the backend cannot report its channels.
+    pub num_active_channels: Option<u32>,
+}
+
+/// Everything rustdress needs from a Lightning node.
+#[async_trait]
+pub trait LightningBackend: Send + Sync {
+    async fn create_invoice(
+        &self,
+        request: InvoiceRequest,
+    ) -> Result<CreatedInvoice, anyhow::Error>;
+
+    /// Resolves once the invoice with `payment_hash` is settled. Returns an error if the
+    /// invoice is canceled or the node stops reporting updates for it.
+    async fn wait_for_settlement(
+        &self,
+        payment_hash: &[u8],
+    ) -> Result<SettledInvoice, anyhow::Error>;
+
+    /// Looks up the invoice with `payment_hash`. Returns an error if the node does not know it.
+    async fn lookup_invoice(&self, payment_hash: &[u8]) -> Result<InvoiceStatus, anyhow::Error>;
+
+    /// Whether `subscribe_settlements` is implemented. Invoices on other nodes are watched one
+    /// by one with `wait_for_settlement`.
+    fn supports_settlement_feed(&self) -> bool {
+        false
+    }
+
+    /// Sends every invoice settled after `settle_index` to `settlements`, oldest first, then
+    /// keeps sending new settlements until the node stops reporting them.
+    async fn subscribe_settlements(
+        &self,
+        _settle_index: u64,
+        _settlements: mpsc::Sender<SettledInvoice>,
+    ) -> Result<(), anyhow::Error> {
+        Err(anyhow!("SettlementFeedNotSupported"))
+    }
+
+    /// Whether `create_invoice` adds the requested blinded paths. Other backends ignore them.
+    fn supports_blinded_paths(&self) -> bool {
+        false
+    }
+
+    /// Whether `create_invoice` can use a preimage chosen by rustdress.
+    fn supports_chosen_preimage(&self) -> bool {
+        true
+    }
+
+    /// Checks that the node is reachable and allowed to create invoices.
+    async fn test_invoice(&self) -> Result<(), anyhow::Error>;
+
+    async fn get_node_info(&self) -> Result<NodeInfo, anyhow::Error>;
+
+    /// Millisatoshis the node can receive right now across its active channels, or `None` when
+    /// the backend cannot tell.
+    async fn get_inbound_capacity(&self) -> Result<Option<i64>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Whether the backend's macaroon grants all of `permissions`, or `None` for backends not
+    /// authenticated with one. An empty list checks only that the macaroon is valid.
+    async fn has_permissions(
+        &self,
+        _permissions: &[Permission],
+    ) -> Result<Option<bool>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Logs where the backend's credentials grant less or more than rustdress needs.
+    async fn audit_permissions(&self, name: &str) -> Result<(), anyhow::Error> {
+        audit_macaroon(self, name).await
+    }
+
+    /// Reconnects with the cert and macaroon on disk when those files changed since they were
+    /// loaded. Returns whether it reconnected.
+    async fn reload_credentials(&self) -> Result<bool, anyhow::Error> {
+        Ok(false)
+    }
+
+    /// Reloads like `reload_credentials`, with a result per node, named, for backends made of
+    /// several.
+    async fn reload_node_credentials(&self) -> Vec<NodeReload> {
+        vec![(None, self.reload_credentials().await)]
+    }
+
+    /// Pays `payment_request`, spending at most `max_fee_msat` on routing. Resolves once the
+    /// payment succeeded or failed.
+    async fn pay_invoice(
+        &self,
+        _payment_request: &str,
+        _max_fee_msat: i64,
+    ) -> Result<PaidInvoice, anyhow::Error> {
+        Err(PaymentFailed::with_cause("PaymentsNotSupported"))
+    }
+
+    /// Bakes a macaroon granting only `permissions`, returned hex encoded.
+    async fn bake_macaroon(&self, _permissions: &[Permission]) -> Result<String, anyhow::Error> {
+        Err(anyhow!("MacaroonBakingNotSupported"))
+    }
+}
+
+pub fn get_backend_kind() -> BackendKind {
+    if is_demo_mode() {
+        BackendKind::Mock
+    } else {
+        get_config().backend.unwrap_or_default()
+    }
+}
+
+/// Whether the backend named `name`, or the default one, has passed its invoice test since
+/// startup.
+pub fn is_backend_ready(name: Option<&str>) -> bool {
+    READY_BACKENDS
+        .lock()
+        .expect("ready backends lock poisoned")
+        .contains(&name.map(str::to_string))
+}
+
+/// What the node is most likely still doing, judging by the error from its invoice test.
+fn waiting_reason(error: &anyhow::Error) -> &'static str {
+    let message = error.to_string();
+
+    if message.contains("wallet locked") {
+        "Waiting for LND to unlock"
+    } else if message.contains("starting up") || message.contains("waiting to start") {
+        "Waiting for LND to finish starting"
+    } else {
+        "Waiting for the Lightning node"
+    }
+}
+
+/// Repeats the invoice test of the backend named `name`, or the default one, with exponential
+/// backoff until it passes, then marks the backend ready.
+pub async fn wait_for_backend(name: Option<String>) {
+    let config = get_config();
+    let backend = get_named_backend(name.as_deref());
+    let label = name.as_deref().unwrap_or("default");
+
+    let max_delay = Duration::from_secs(
+        config
+            .startup_retry_max_secs
+            .unwrap_or(DEFAULT_STARTUP_RETRY_MAX_SECS),
+    );
+    let mut delay = Duration::from_secs(
+        config
+            .startup_retry_initial_secs
+            .unwrap_or(DEFAULT_STARTUP_RETRY_INITIAL_SECS),
+    )
+    .min(max_delay);
+
+    for attempt in 1.. {
+        let error = match timeout(STARTUP_TEST_TIMEOUT, backend.test_invoice()).await {
+            Ok(Ok(())) => break,
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("InvoiceTestTimedOut"),
+        };
+
+        info!(
+            target: "credentials::lightning_backend",
+            "{} for the {} backend (attempt {}, retrying in {}s): {}",
+            waiting_reason(&error),
+            label,
+            attempt,
+            delay.as_secs(),
+            error
+        );
+        sleep(delay).await;
+        delay = (delay * 2).min(max_delay);
+    }
+
+    info!(target: "credentials::lightning_backend", "The {} Lightning backend is ready", label);
+    READY_BACKENDS
+        .lock()
+        .expect("ready backends lock poisoned")
+        .insert(name);
+}
+
+/// The default backend, created on first use and shared by every request after that.
+pub fn get_backend() -> Arc<dyn LightningBackend> {
+    cached_backend(None, || {
+        let config = get_config();
+        let kind = get_backend_kind();
+        info!(target: "credentials::lightning_backend", "Using {:?} lightning backend", kind);
+
+        match (kind, &config.lnd_nodes) {
+            (BackendKind::Lnd, Some(nodes)) if !nodes.is_empty() => lnd_failover(),
+            _ => new_backend(
+                kind,
+                config.lnd.as_ref(),
+                config.cln.as_ref(),
+                config.nwc.as_ref(),
+            ),
+        }
+    })
+}
+
+/// The `[[backends]]` entry receiving the user's payments, or `None` for the default backend.
+pub fn get_user_backend_name(username: &str) -> Option<String> {
+    if is_demo_mode() {
+        return None;
+    }
+
+    let config = get_config();
+    let backend_name = config
+        .users
+        .iter()
+        .find(|u| u.username == username)
+        .and_then(|u| u.backend.as_ref())?;
+
+    if config
+        .backends
+        .iter()
+        .flatten()
+        .any(|b| &b.name == backend_name)
+    {
+        debug!(target: "credentials::lightning_backend", "Using backend {} for {}", backend_name, username);
+        Some(backend_name.clone())
+    } else {
+        warn!(target: "credentials::lightning_backend", "Unknown backend {} for {}, using the default", backend_name, username);
+        None
+    }
+}
+
+/// The backend named by the user's `backend` setting, or the default one.
+pub fn get_user_backend(username: &str) -> Arc<dyn LightningBackend> {
+    get_named_backend(get_user_backend_name(username).as_deref())
+}
+
+/// The `[[backends]]` entry called `name`, or the default backend when there is none.
+pub fn get_named_backend(name: Option<&str>) -> Arc<dyn LightningBackend> {
+    let named = name.and_then(|name| {
+        get_config()
+            .backends
+            .iter()
+            .flatten()
+            .find(|b| b.name == name)
+    });
+
+    match named {
+        Some(named) => cached_backend(Some(&named.name), || {
+            new_backend(
+                named.kind,
+                named.lnd.as_ref(),
+                named.cln.as_ref(),
+                named.nwc.as_ref(),
+            )
+        }),
+        None => get_backend(),
+    }
+}
+
+/// Every backend created so far, keyed like `get_named_backend`.
+pub fn cached_backends() -> Vec<(Option<String>, SharedBackend)> {
+    BACKENDS
+        .lock()
+        .expect("backend cache lock poisoned")
+        .iter()
+        .map(|(name, backend)| (name.clone(), Arc::clone(backend)))
+        .collect()
+}
+
+/// The cached backend called `name`, made with `create` on first use. Backends are made under
+/// their own lock, so one that fails to build never poisons the cache other requests read.
+fn cached_backend(
+    name: Option<&str>,
+    create: impl FnOnce() -> Arc<dyn LightningBackend>,
+) -> Arc<dyn LightningBackend> {
+    let key = name.map(str::to_string);
+    let cached = || {
+        BACKENDS
+            .lock()
+            .expect("backend cache lock poisoned")
+            .get(&key)
+            .cloned()
+    };
+
+    if let Some(backend) = cached() {
+        return backend;
+    }
+
+    let _creating = CREATING_BACKEND
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some(backend) = cached() {
+        return backend;
+    }
+
+    let backend = create();
+    BACKENDS
+        .lock()
+        .expect("backend cache lock poisoned")
+        .insert(key, Arc::clone(&backend));
+
+    backend
+}
+
+/// Checks that every backend in use has its section in the config, so a mistake stops
+/// rustdress at startup rather than failing invoices later.
+pub fn check_backend_config() -> Result<(), anyhow::Error> {
+    let config = get_config();
+    let kind = get_backend_kind();
+
+    // A zero interval would retry, probe or poll without ever sleeping.
+    for (setting, value) in [
+        (
+            "startup_retry_initial_secs",
+            config.startup_retry_initial_secs,
+        ),
+        ("startup_retry_max_secs", config.startup_retry_max_secs),
+        (
+            "health_check_interval_secs",
+            config.health_check_interval_secs,
+        ),
+        (
+            "credential_watch_interval_secs",
+            config.credential_watch_interval_secs,
+        ),
+    ] {
+        if value == Some(0) {
+            return Err(anyhow!(
+                "ZeroIntervalInConfig: {} must be at least 1",
+                setting
+            ));
+        }
+    }
+
+    let fails_over = kind == BackendKind::Lnd
+        && config
+            .lnd_nodes
+            .as_ref()
+            .is_some_and(|nodes| !nodes.is_empty());
+    if !fails_over {
+        check_backend_sections(
+            "the default backend",
+            kind,
+            config.lnd.as_ref(),
+            config.cln.as_ref(),
+            config.nwc.as_ref(),
+        )?;
+    }
+
+    // Demo mode routes every user to the mock backend.
+    if is_demo_mode() {
+        return Ok(());
+    }
+
+    for backend in config.backends.iter().flatten() {
+        check_backend_sections(
+            &backend.name,
+            backend.kind,
+            backend.lnd.as_ref(),
+            backend.cln.as_ref(),
+            backend.nwc.as_ref(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn check_backend_sections(
+    name: &str,
+    kind: BackendKind,
+    lnd: Option<&Lnd>,
+    cln: Option<&Cln>,
+    nwc: Option<&Nwc>,
+) -> Result<(), anyhow::Error> {
+    match (kind, lnd, cln, nwc) {
+        (BackendKind::Lnd | BackendKind::LndRest, None, _, _) => {
+            Err(anyhow!("ExpectedLndSectionInConfig for {}", name))
+        }
+        (BackendKind::Cln, _, None, _) => Err(anyhow!("ExpectedClnSectionInConfig for {}", name)),
+        (BackendKind::Nwc, _, _, None) => Err(anyhow!("ExpectedNwcSectionInConfig for {}", name)),
+        (BackendKind::Nwc, _, _, Some(nwc)) => {
+            NwcBackend::from_connection_string(&nwc.connection_string)
+                .map(|_| ())
+                .map_err(|e| anyhow!("FailedToParseNwcConnectionString for {}: {}", name, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sections are checked by `check_backend_config` at startup.
+fn new_backend(
+    kind: BackendKind,
+    lnd: Option<&Lnd>,
+    cln: Option<&Cln>,
+    nwc: Option<&Nwc>,
+) -> Arc<dyn LightningBackend> {
+    match kind {
+        BackendKind::Lnd => Arc::new(LndBackend::new(lnd.expect("ExpectedLndSectionInConfig"))),
+        BackendKind::LndRest => Arc::new(LndRestBackend::connect(
+            lnd.expect("ExpectedLndSectionInConfig"),
+        )),
+        BackendKind::Cln => Arc::new(ClnBackend::connect(
+            cln.expect("ExpectedClnSectionInConfig"),
+        )),
+        BackendKind::Nwc => Arc::new(NwcBackend::connect(
+            nwc.expect("ExpectedNwcSectionInConfig"),
+        )),
+        BackendKind::Mock => get_mock(),
+    }
+}
+
+/// A failover set built from `[[lnd_nodes]]`, plus `[lnd]` as a node named "default" when
+/// present. Health checks start as soon as it is built.
+fn lnd_failover() -> Arc<dyn LightningBackend> {
+    let config = get_config();
+    let mut nodes = vec![];
+
+    if let Some(lnd_config) = &config.lnd {
+        let backend = LndBackend::new(lnd_config);
+        nodes.push(FailoverNode::new(
+            "default".to_string(),
+            0,
+            Arc::new(backend),
+        ));
+    }
+
+    for node in config.lnd_nodes.iter().flatten() {
+        let backend = LndBackend::new(&node.lnd);
+        nodes.push(FailoverNode::new(
+            node.name.clone(),
+            node.priority,
+            Arc::new(backend),
+        ));
+    }
+    info!(target: "credentials::lightning_backend", "Failing over between {} LND nodes", nodes.len());
+
+    let failover = Arc::new(FailoverBackend::new(nodes));
+    let interval = config
+        .health_check_interval_secs
+        .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL_SECS);
+    failover.spawn_health_checks(Duration::from_secs(interval));
+
+    failover
+}
@@ -0,0 +1,147 @@
+use futures::future::join_all;
+use tracing::{error, info, warn};
+
+use crate::credentials::lightning_backend::LightningBackend;
+
+/// An LND macaroon permission, such as `invoices:write`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permission {
+    pub entity: &'static str,
+    pub action: &'static str,
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.entity, self.action)
+    }
+}
+
+const fn permission(entity: &'static str, action: &'static str) -> Permission {
+    Permission { entity, action }
+}
+
+/// What creating invoices and following their settlement needs. Baked macaroons grant exactly
+/// these.
+pub const REQUIRED_PERMISSIONS: &[Permission] = &[
+    permission("invoices", "read"),
+    permission("invoices", "write"),
+];
+
+/// Used when granted, each by one feature that is skipped without it.
+const OPTIONAL_PERMISSIONS: &[(Permission, &str)] = &[
+    (
+        permission("info", "read"),
+        "node info in the startup log and readiness checks",
+    ),
+    (
+        permission("offchain", "read"),
+        "capping maxSendable at the inbound capacity",
+    ),
+    (
+        permission("offchain", "write"),
+        "paying out withdraw vouchers",
+    ),
+];
+
+/// Added to baked macaroons when withdraw vouchers are enabled.
+pub const VOUCHER_PERMISSIONS: &[Permission] = &[permission("offchain", "write")];
+
+/// Everything else LND can grant. None of it is used by rustdress.
+const EXCESS_PERMISSIONS: &[Permission] = &[
+    permission("onchain", "read"),
+    permission("onchain", "write"),
+    permission("address", "read"),
+    permission("address", "write"),
+    permission("message", "read"),
+    permission("message", "write"),
+    permission("peers", "read"),
+    permission("peers", "write"),
+    permission("info", "write"),
+    permission("signer", "read"),
+    permission("signer", "generate"),
+    permission("macaroon", "read"),
+    permission("macaroon", "write"),
+    permission("macaroon", "generate"),
+];
+
+/// Whether LND refused a call because the macaroon lacks a permission.
+pub fn is_permission_denied(error: &anyhow::Error) -> bool {
+    error.to_string().contains("permission denied")
+}
+
+async fn granted(
+    backend: &(impl LightningBackend + ?Sized),
+    permissions: &[Permission],
+) -> Vec<bool> {
+    join_all(permissions.iter().map(|permission| async move {
+        matches!(
+            backend
+                .has_permissions(std::slice::from_ref(permission))
+                .await,
+            Ok(Some(true))
+        )
+    }))
+    .await
+}
+
+/// Compares the backend's macaroon with what rustdress needs, and warns about anything missing
+/// or granted beyond it.
+pub async fn audit_macaroon(
+    backend: &(impl LightningBackend + ?Sized),
+    name: &str,
+) -> Result<(), anyhow::Error> {
+    // Checking permissions needs `macaroon:read` itself, which a least-privilege macaroon
+    // does not have.
+    match backend.has_permissions(&[]).await {
+        Ok(Some(true)) => {}
+        Ok(Some(false)) => {
+            error!(target: "credentials::macaroon_audit", "The macaroon for {} is not valid for this node", name);
+            return Ok(());
+        }
+        Ok(None) => return Ok(()),
+        Err(e) if is_permission_denied(&e) => {
+            info!(target: "credentials::macaroon_audit", "The macaroon for {} cannot list its own permissions, so it grants no macaroon access", name);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+
+    for (permission, granted) in REQUIRED_PERMISSIONS
+        .iter()
+        .zip(granted(backend, REQUIRED_PERMISSIONS).await)
+    {
+        if !granted {
+            error!(target: "credentials::macaroon_audit", "The macaroon for {} lacks {}, which rustdress needs to receive payments", name, permission);
+        }
+    }
+
+    let optional: Vec<Permission> = OPTIONAL_PERMISSIONS.iter().map(|(p, _)| *p).collect();
+    for ((permission, feature), granted) in OPTIONAL_PERMISSIONS
+        .iter()
+        .zip(granted(backend, &optional).await)
+    {
+        if !granted {
+            info!(target: "credentials::macaroon_audit", "The macaroon for {} lacks {}, skipping {}", name, permission, feature);
+        }
+    }
+
+    let excess: Vec<String> = EXCESS_PERMISSIONS
+        .iter()
+        .zip(granted(backend, EXCESS_PERMISSIONS).await)
+        .filter(|(_, granted)| *granted)
+        .map(|(permission, _)| permission.to_string())
+        .collect();
+
+    if excess.is_empty() {
+        info!(target: "credentials::macaroon_audit", "The macaroon for {} grants nothing beyond what rustdress needs", name);
+    } else {
+        warn!(
+            target: "credentials::macaroon_audit",
+            "!!! The macaroon for {} grants {}, which rustdress never uses. Anyone who reads it can use them against your node. Bake a macaroon limited to invoices with `rustdress --bake-macaroon <path>` and point macaroon_path at it.",
+            name,
+            excess.join(", ")
+        );
+    }
+
+    Ok(())
+}
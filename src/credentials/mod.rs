@@ -1,4 +1,12 @@
+pub mod failover;
 pub mod get_cert;
+pub mod get_cln;
 pub mod get_lnd;
+pub mod get_lnd_rest;
 pub mod get_macaroon;
+pub mod get_mock;
+pub mod get_nwc;
 pub mod get_socket;
+pub mod lightning_backend;
+pub mod macaroon_audit;
+pub mod reload;
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Lnd, get_config},
+    credentials::lightning_backend::cached_backends,
+};
+
+const DEFAULT_CREDENTIAL_WATCH_INTERVAL_SECS: u64 = 10;
+
+/// The latest reload of each backend, or each node of a failover backend, by name.
+static LAST_RELOADS: Lazy<Mutex<HashMap<String, CredentialReload>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The outcome of a reload after a cert or macaroon file changed.
+#[derive(Serialize, Clone)]
+pub struct CredentialReload {
+    pub backend: String,
+    pub reloaded_at: u64,
+    pub status: &'static str,
+    pub detail: String,
+}
+
+/// The latest reload of every backend and node that had one, by name.
+pub fn get_last_reloads() -> Vec<CredentialReload> {
+    let mut reloads: Vec<CredentialReload> = LAST_RELOADS
+        .lock()
+        .expect("reload lock poisoned")
+        .values()
+        .cloned()
+        .collect();
+    reloads.sort_by(|a, b| a.backend.cmp(&b.backend));
+
+    reloads
+}
+
+type Stamps = Vec<Option<SystemTime>>;
+
+/// The cert and macaroon files of an LND config, and when they were last loaded.
+pub struct CredentialFiles {
+    paths: Vec<String>,
+    loaded: Mutex<Stamps>,
+}
+
+impl CredentialFiles {
+    pub fn new(lnd_config: &Lnd) -> Self {
+        let paths: Vec<String> = [&lnd_config.cert_path, &lnd_config.macaroon_path]
+            .into_iter()
+            .flatten()
+            .filter(|path| !path.is_empty())
+            .cloned()
+            .collect();
+        let loaded = Mutex::new(stamps(&paths));
+
+        CredentialFiles { paths, loaded }
+    }
+
+    /// The files' current modification times, when any differ from the loaded ones.
+    pub fn changed(&self) -> Option<Stamps> {
+        let current = stamps(&self.paths);
+        let loaded = self.loaded.lock().expect("credential files lock poisoned");
+
+        (current != *loaded).then_some(current)
+    }
+
+    /// Records that the files as of `stamps` are in use. Until then every check reports them
+    /// changed, so a reload that failed on a half-written file is retried.
+    pub fn mark_loaded(&self, stamps: Stamps) {
+        *self.loaded.lock().expect("credential files lock poisoned") = stamps;
+    }
+}
+
+fn stamps(paths: &[String]) -> Stamps {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn record(backend: &str, result: &Result<bool, anyhow::Error>) {
+    let (status, detail) = match result {
+        Ok(false) => return,
+        Ok(true) => (
+            "ok",
+            "Reconnected with the new cert and macaroon".to_string(),
+        ),
+        Err(e) => ("fail", e.to_string()),
+    };
+
+    LAST_RELOADS.lock().expect("reload lock poisoned").insert(
+        backend.to_string(),
+        CredentialReload {
+            backend: backend.to_string(),
+            reloaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            status,
+            detail,
+        },
+    );
+}
+
+/// Polls the cert and macaroon files of every backend in use, and reconnects a backend when
+/// its files change.
+pub fn start_credential_watch() {
+    let interval = Duration::from_secs(
+        get_config()
+            .credential_watch_interval_secs
+            .unwrap_or(DEFAULT_CREDENTIAL_WATCH_INTERVAL_SECS),
+    );
+
+    tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+
+            for (name, backend) in cached_backends() {
+                let backend_name = name.unwrap_or_else(|| "default".to_string());
+
+                // Each node of a failover backend reloads, and is reported, on its own.
+                for (node, result) in backend.reload_node_credentials().await {
+                    let name = match node {
+                        Some(node) => format!("{}/{}", backend_name, node),
+                        None => backend_name.clone(),
+                    };
+
+                    match &result {
+                        Ok(true) => {
+                            info!(target: "credentials::reload", "Reloaded the cert and macaroon for {}", name)
+                        }
+                        Ok(false) => {
+                            debug!(target: "credentials::reload", "Credentials for {} unchanged", name)
+                        }
+                        Err(e) => {
+                            warn!(target: "credentials::reload", "Failed to reload the cert and macaroon for {}: {}", name, e)
+                        }
+                    }
+                    record(&name, &result);
+                }
+            }
+        }
+    });
+}
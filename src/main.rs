@@ -1,11 +1,25 @@
-use credentials::get_lnd::{get_lnd, test_invoice};
-use server::{start_server::start_server, utils::nip05_broadcast};
+use credentials::{
+    lightning_backend::{
+        check_backend_config, get_backend, get_user_backend, get_user_backend_name,
+        wait_for_backend,
+    },
+    macaroon_audit::{REQUIRED_PERMISSIONS, VOUCHER_PERMISSIONS},
+    reload::start_credential_watch,
+};
+use server::{
+    admin::is_admin_enabled,
+    metadata::load_avatars,
+    start_server::start_server,
+    utils::{check_invoice_policies, nip05_broadcast},
+    zap_receipts::{load_zap_state, start_zap_receipts},
+};
 mod config;
 mod server;
 
 mod credentials;
-use crate::config::get_config;
-use tracing::{info, Level};
+use crate::config::{get_bake_macaroon_path, get_config};
+use std::{collections::HashSet, fs};
+use tracing::{info, warn, Level};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 #[tokio::main]
@@ -26,15 +40,22 @@ async fn main() -> Result<(), anyhow::Error> {
         .init();
 
     info!("Starting Rustdress application");
+    check_backend_config()?;
+    check_invoice_policies()?;
+    load_zap_state()?;
+
+    if let Some(path) = get_bake_macaroon_path() {
+        return bake_macaroon(path).await;
+    }
 
     let config = get_config();
     let domain = config.domain.clone();
 
-    info!("Connecting to LND node");
-    let lnd = get_lnd().await;
+    info!("Connecting to Lightning node");
+    tokio::spawn(check_backends());
+    start_credential_watch();
 
-    info!("Testing invoice generation");
-    test_invoice(lnd).await?;
+    load_avatars()?;
 
     info!("Broadcasting NIP-05 verification");
     for user in &config.users {
@@ -46,3 +67,66 @@ async fn main() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+/// Waits for the default backend, then starts zap receipts and reports on every backend.
+/// Backends of `[[backends]]` users are waited for alongside. Invoice requests are refused
+/// until the backend of their user is ready.
+async fn check_backends() {
+    let named: HashSet<String> = get_config()
+        .users
+        .iter()
+        .filter_map(|u| get_user_backend_name(&u.username))
+        .collect();
+    for name in named {
+        tokio::spawn(wait_for_backend(Some(name)));
+    }
+
+    wait_for_backend(None).await;
+    start_zap_receipts();
+
+    if let Err(e) = get_backend().audit_permissions("the default backend").await {
+        warn!("Failed to audit the macaroon: {}", e);
+    }
+
+    match get_backend().get_node_info().await {
+        Ok(node) => info!(
+            "Connected to {} ({}) running {}. Block height: {}, synced to chain: {}, active channels: {}",
+            node.alias,
+            node.pubkey,
+            node.version,
+            node.block_height,
+            node.synced_to_chain,
+            node.num_active_channels
+                .map_or("unknown".to_string(), |n| n.to_string())
+        ),
+        Err(e) => warn!("Failed to fetch node info: {}", e),
+    }
+
+    for user in get_config().users.iter().filter(|u| u.backend.is_some()) {
+        let backend = get_user_backend(&user.username);
+        if let Err(e) = backend.audit_permissions(&user.username).await {
+            warn!("Failed to audit the macaroon for {}: {}", user.username, e);
+        }
+    }
+}
+
+/// Bakes a macaroon granting only what rustdress needs on the default backend, so it can
+/// replace `admin.macaroon` in the config.
+async fn bake_macaroon(path: &str) -> Result<(), anyhow::Error> {
+    let mut permissions = REQUIRED_PERMISSIONS.to_vec();
+    if is_admin_enabled() {
+        info!("Baking a macaroon limited to invoices and paying withdraw vouchers");
+        permissions.extend_from_slice(VOUCHER_PERMISSIONS);
+    } else {
+        info!("Baking a macaroon limited to invoices");
+    }
+
+    let macaroon = get_backend().bake_macaroon(&permissions).await?;
+    fs::write(path, hex::decode(macaroon)?)?;
+    info!(
+        "Wrote the macaroon to {}. Set macaroon_path to it and restart rustdress.",
+        path
+    );
+
+    Ok(())
+}
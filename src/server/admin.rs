@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::{Body, Request, header::AUTHORIZATION};
+use once_cell::sync::Lazy;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::get_config,
+    server::{
+        lnurl_auth::verify_signature,
+        utils::{bech32_encode, get_identifiers},
+    },
+};
+
+/// How long a login can be completed with a wallet after it starts.
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+const DEFAULT_ADMIN_SESSION_TTL_SECS: u64 = 12 * 60 * 60;
+
+/// Anyone can start a login, so only this many are kept waiting; beyond that new logins are
+/// refused until some expire.
+const MAX_PENDING_LOGINS: usize = 100;
+
+/// LNURL-auth sessions by the SHA256 of their token.
+static SESSIONS: Lazy<Mutex<HashMap<String, Session>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A session token handed out with a login challenge. It grants admin access once an admin's
+/// wallet signs the challenge.
+struct Session {
+    k1: String,
+    /// The admin's linking key, once the challenge is signed.
+    linking_key: Option<String>,
+    expires_at: Instant,
+}
+
+impl Session {
+    fn is_active(&self) -> bool {
+        self.linking_key.is_some() && self.expires_at > Instant::now()
+    }
+}
+
+fn admin_token() -> Option<&'static str> {
+    get_config()
+        .admin_token
+        .as_deref()
+        .filter(|token| !token.is_empty())
+}
+
+fn admin_keys() -> &'static [String] {
+    get_config().admin_keys.as_deref().unwrap_or_default()
+}
+
+fn digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+fn bearer_token(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Whether the `/admin` endpoints can be used at all.
+pub fn is_admin_enabled() -> bool {
+    admin_token().is_some() || !admin_keys().is_empty()
+}
+
+/// Whether `req` carries the configured `admin_token`, or the token of a signed-in session, as
+/// a bearer token.
+pub fn is_admin(req: &Request<Body>) -> bool {
+    let Some(presented) = bearer_token(req) else {
+        return false;
+    };
+
+    // Comparing digests keeps the time taken independent of how much of the token matched.
+    let presented = digest(presented);
+    if admin_token().is_some_and(|token| digest(token) == presented) {
+        return true;
+    }
+
+    SESSIONS
+        .lock()
+        .expect("admin session lock poisoned")
+        .get(&presented)
+        .is_some_and(Session::is_active)
+}
+
+/// Starts an LNURL-auth (LUD-04) login. The `lnurl` is for an admin's wallet to sign; the
+/// `token` stays with the client and works as a bearer token once the wallet has signed.
+pub fn begin_login() -> Result<Value, String> {
+    if admin_keys().is_empty() {
+        return Err("AdminLoginNotConfigured".to_string());
+    }
+
+    // Kept apart from the pay request challenges, which payers could use to push it out.
+    let k1 = hex::encode(rand::random::<[u8; 32]>());
+
+    let (domain, _) = get_identifiers(None);
+    let url = format!(
+        "https://{}/admin/login/callback?tag=login&k1={}&action=login",
+        domain, k1
+    );
+    let lnurl = bech32_encode("lnurl".to_string(), url.clone())
+        .map_err(|_| "FailedToEncodeLnurl".to_string())?;
+
+    let token = add_pending_login(&k1)?;
+    debug!(target: "server::admin", "Started admin login with k1 {}", k1);
+
+    Ok(json!({
+        "k1": k1,
+        "lnurl": lnurl,
+        "url": url,
+        "token": token,
+    }))
+}
+
+/// Stores a session waiting on `k1` to be signed, and returns its token.
+fn add_pending_login(k1: &str) -> Result<String, String> {
+    let mut sessions = SESSIONS.lock().expect("admin session lock poisoned");
+    insert_pending_login(&mut sessions, k1)
+}
+
+fn insert_pending_login(
+    sessions: &mut HashMap<String, Session>,
+    k1: &str,
+) -> Result<String, String> {
+    sessions.retain(|_, session| session.expires_at > Instant::now());
+
+    let pending = sessions
+        .values()
+        .filter(|session| session.linking_key.is_none())
+        .count();
+    if pending >= MAX_PENDING_LOGINS {
+        warn!(target: "server::admin", "Refusing admin login, {} already pending", pending);
+        return Err("TooManyPendingLogins".to_string());
+    }
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    sessions.insert(
+        digest(&token),
+        Session {
+            k1: k1.to_string(),
+            linking_key: None,
+            expires_at: Instant::now() + LOGIN_TTL,
+        },
+    );
+
+    Ok(token)
+}
+
+/// Signs in the session waiting on `k1` when `key` is an admin's linking key and `sig` its
+/// signature of the challenge.
+pub fn complete_login(k1: &str, sig: &str, key: &str) -> Result<(), String> {
+    verify_signature(k1, sig, key)?;
+
+    if !admin_keys()
+        .iter()
+        .any(|admin| admin.eq_ignore_ascii_case(key))
+    {
+        warn!(target: "server::admin", "Login signed by {}, which is not an admin key", key);
+        return Err("LinkingKeyNotAuthorized".to_string());
+    }
+
+    let ttl = Duration::from_secs(
+        get_config()
+            .admin_session_ttl_secs
+            .unwrap_or(DEFAULT_ADMIN_SESSION_TTL_SECS),
+    );
+
+    let mut sessions = SESSIONS.lock().expect("admin session lock poisoned");
+    let session = sessions
+        .values_mut()
+        .find(|session| {
+            session.k1 == k1 && session.linking_key.is_none() && session.expires_at > Instant::now()
+        })
+        .ok_or_else(|| {
+            warn!(target: "server::admin", "Unknown or expired k1 {}", k1);
+            "UnknownOrExpiredK1".to_string()
+        })?;
+    session.linking_key = Some(key.to_lowercase());
+    session.expires_at = Instant::now() + ttl;
+    info!(target: "server::admin", "Admin {} signed in", key);
+
+    Ok(())
+}
+
+/// The signed-in session `req` authenticates with, if any.
+pub fn get_session(req: &Request<Body>) -> Option<Value> {
+    let presented = digest(bearer_token(req)?);
+    let sessions = SESSIONS.lock().expect("admin session lock poisoned");
+    let session = sessions
+        .get(&presented)
+        .filter(|session| session.is_active())?;
+
+    Some(json!({
+        "linking_key": session.linking_key,
+        "expires_in_secs": session.expires_at.saturating_duration_since(Instant::now()).as_secs(),
+    }))
+}
+
+/// Ends the session `req` authenticates with. Returns whether there was one.
+pub fn end_session(req: &Request<Body>) -> bool {
+    let Some(presented) = bearer_token(req) else {
+        return false;
+    };
+
+    SESSIONS
+        .lock()
+        .expect("admin session lock poisoned")
+        .remove(&digest(presented))
+        .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init_test_config;
+
+    /// The admin key of the test config, and challenges it signed.
+    const ADMIN_KEY: &str = "02e10e6188de29e212ef7d74644b9822d403283127432fa115bf3cbde61aead0dd";
+    const K1: &str = "6ab9f1eb8f7d3388f4f9d586f66e99fd54080df2c446f0e58668b09c08a16dd0";
+    const SIG: &str = "3044022017f53289eac961e5adc858d3ca50dab056ddca7a1a906c0815a0369312d1aa49022061f0bcd55129f1721020171a9f64844b11bd58def5bd990dbd906c4cc2b4ba41";
+    const UNKNOWN_K1: &str = "b23a6a8439c0dde5515893e7c90c1e3233b8616e634470f20dc4928bcf3609bc";
+    const UNKNOWN_K1_SIG: &str = "3044022041ef23366a849f5c44f660ebed8d339e4522cc81a46e4a7d8a329d5ef41a8f09022038ca8c9ef6be42fcdea0ddc4aa1aa9ee451c14563b8e986eebebe3dde1010a86";
+    const EXPIRED_K1: &str = "fa64ea1e82e1206f828ab2a02917c7e92accb98e3b95881a1b4ad52b914b66e3";
+    const EXPIRED_K1_SIG: &str = "3045022100f2abfa24ab4dadb91aa105b8709e21bf6c5eeb45c13e6b17974a856faa0f4cb4022076b7fd420d58021cb4a58e398c422cd8cd0cc7bbb045f540c5f0be6aa953ad70";
+
+    /// A key that is not an admin's, and its signature of `K1`.
+    const OTHER_KEY: &str = "03b36311a9e4a10d48c1188d9254db2a1c8d13d7d2e75b609b7e50758418f262f6";
+    const OTHER_SIG: &str = "3045022100f71ce8d34820e19b0510250d5a6f8e79d19c906b1c4b5f10f7ef058ef20629e10220682aecfc98469e9c7e5c61d0884ff9352e29d5e6e45ee1700070e835d414bdd4";
+
+    fn request(token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        builder.body(Body::empty()).expect("valid request")
+    }
+
+    fn add_session(linking_key: Option<&str>, expires_at: Instant) -> String {
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        SESSIONS
+            .lock()
+            .expect("admin session lock poisoned")
+            .insert(
+                digest(&token),
+                Session {
+                    k1: hex::encode(rand::random::<[u8; 32]>()),
+                    linking_key: linking_key.map(str::to_string),
+                    expires_at,
+                },
+            );
+
+        token
+    }
+
+    #[test]
+    fn signs_in_admin() {
+        init_test_config();
+        let token = add_pending_login(K1).unwrap();
+        assert!(!is_admin(&request(Some(&token))));
+
+        assert_eq!(complete_login(K1, SIG, ADMIN_KEY), Ok(()));
+        assert!(is_admin(&request(Some(&token))));
+    }
+
+    #[test]
+    fn refuses_key_that_is_not_an_admin() {
+        init_test_config();
+        assert_eq!(
+            complete_login(K1, OTHER_SIG, OTHER_KEY),
+            Err("LinkingKeyNotAuthorized".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_k1() {
+        init_test_config();
+        assert_eq!(
+            complete_login(UNKNOWN_K1, UNKNOWN_K1_SIG, ADMIN_KEY),
+            Err("UnknownOrExpiredK1".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_expired_k1() {
+        init_test_config();
+        let token = add_pending_login(EXPIRED_K1).unwrap();
+        SESSIONS
+            .lock()
+            .expect("admin session lock poisoned")
+            .get_mut(&digest(&token))
+            .expect("pending login")
+            .expires_at = Instant::now() - Duration::from_secs(1);
+
+        assert_eq!(
+            complete_login(EXPIRED_K1, EXPIRED_K1_SIG, ADMIN_KEY),
+            Err("UnknownOrExpiredK1".to_string())
+        );
+        assert!(!is_admin(&request(Some(&token))));
+    }
+
+    #[test]
+    fn accepts_only_active_sessions() {
+        init_test_config();
+        let in_a_minute = Instant::now() + Duration::from_secs(60);
+        let active = add_session(Some(ADMIN_KEY), in_a_minute);
+        let pending = add_session(None, in_a_minute);
+        let expired = add_session(Some(ADMIN_KEY), Instant::now() - Duration::from_secs(1));
+
+        assert!(is_admin(&request(Some(&active))));
+        assert!(!is_admin(&request(Some(&pending))));
+        assert!(!is_admin(&request(Some(&expired))));
+        assert!(!is_admin(&request(Some("unknown token"))));
+        assert!(!is_admin(&request(None)));
+    }
+
+    #[test]
+    fn refuses_logins_beyond_cap() {
+        let mut sessions = HashMap::new();
+        let tokens: Vec<String> = (0..MAX_PENDING_LOGINS)
+            .map(|_| insert_pending_login(&mut sessions, K1).unwrap())
+            .collect();
+
+        assert_eq!(
+            insert_pending_login(&mut sessions, K1),
+            Err("TooManyPendingLogins".to_string())
+        );
+        assert!(
+            tokens
+                .iter()
+                .all(|token| sessions.contains_key(&digest(token)))
+        );
+
+        sessions
+            .get_mut(&digest(&tokens[0]))
+            .expect("pending login")
+            .expires_at = Instant::now() - Duration::from_secs(1);
+        assert!(insert_pending_login(&mut sessions, K1).is_ok());
+    }
+
+    #[test]
+    fn accepts_admin_token() {
+        init_test_config();
+        assert!(is_admin(&request(Some("test admin token"))));
+    }
+}
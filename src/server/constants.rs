@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
 pub struct Constants {
+    pub inbound_safety_margin_msat: i64,
+    pub invoice_expiry_secs: i64,
     pub max_comment_length: usize,
     pub max_sendamount: i64,
     pub min_sendamount: i64,
@@ -24,6 +26,8 @@ pub const CONSTANTS: Constants = Constants {
         "wss://nostr.oxtr.dev",
         "wss://no.str.cr",
     ],
+    inbound_safety_margin_msat: 1000000,
+    invoice_expiry_secs: 300,
     max_comment_length: 280,
     max_sendamount: 10000000000,
     min_sendamount: 1000,
@@ -0,0 +1,75 @@
+use aes::Aes256;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+use secp256k1::{PublicKey, SecretKey, ecdh::shared_secret_point};
+use tracing::error;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+
+pub fn aes256_cbc_encrypt(key: &[u8; 32], iv: &[u8; 16], plaintext: &[u8]) -> Vec<u8> {
+    Aes256CbcEnc::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(plaintext)
+}
+
+pub fn aes256_cbc_decrypt(
+    key: &[u8; 32],
+    iv: &[u8; 16],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, String> {
+    Aes256CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|e| {
+            error!(target: "server::encryption", "Failed to decrypt ciphertext: {}", e);
+            "FailedToDecryptCiphertext".to_string()
+        })
+}
+
+/// NIP-04 shared key: the unhashed x coordinate of the ECDH point.
+fn nip04_shared_key(privkey: &str, pubkey: &str) -> Result<[u8; 32], String> {
+    let secret_key = hex::decode(privkey)
+        .ok()
+        .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+        .ok_or_else(|| "InvalidNostrPrivateKey".to_string())?;
+
+    let mut compressed = vec![0x02];
+    compressed.extend(hex::decode(pubkey).map_err(|_| "InvalidNostrPublicKey".to_string())?);
+    let public_key =
+        PublicKey::from_slice(&compressed).map_err(|_| "InvalidNostrPublicKey".to_string())?;
+
+    let point = shared_secret_point(&public_key, &secret_key);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&point[..32]);
+    Ok(key)
+}
+
+pub fn nip04_encrypt(privkey: &str, pubkey: &str, plaintext: &str) -> Result<String, String> {
+    let key = nip04_shared_key(privkey, pubkey)?;
+    let iv: [u8; 16] = rand::random();
+    let ciphertext = aes256_cbc_encrypt(&key, &iv, plaintext.as_bytes());
+
+    Ok(format!(
+        "{}?iv={}",
+        STANDARD.encode(ciphertext),
+        STANDARD.encode(iv)
+    ))
+}
+
+pub fn nip04_decrypt(privkey: &str, pubkey: &str, content: &str) -> Result<String, String> {
+    let (ciphertext, iv) = content
+        .split_once("?iv=")
+        .ok_or_else(|| "InvalidNip04Content".to_string())?;
+
+    let ciphertext = STANDARD
+        .decode(ciphertext)
+        .map_err(|_| "InvalidNip04Content".to_string())?;
+    let iv: [u8; 16] = STANDARD
+        .decode(iv)
+        .ok()
+        .and_then(|iv| iv.try_into().ok())
+        .ok_or_else(|| "InvalidNip04Iv".to_string())?;
+
+    let key = nip04_shared_key(privkey, pubkey)?;
+    let plaintext = aes256_cbc_decrypt(&key, &iv, &ciphertext)?;
+
+    String::from_utf8(plaintext).map_err(|_| "InvalidNip04Plaintext".to_string())
+}
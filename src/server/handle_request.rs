@@ -2,18 +2,37 @@ use crate::server::{constants::CONSTANTS, utils::bech32_encode};
 use http::uri::Uri;
 use hyper::{http, Body, Request, Response};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{Value, json};
+use std::time::Duration;
+use tokio::time::timeout;
 use tracing::{debug, error, info, warn};
 
 use super::{
+    admin::{begin_login, complete_login, end_session, get_session, is_admin},
+    health::check_readiness,
     parsing_functions::{
-        convert_key, find_key, get_digest, handle_bad_request, handle_ok_request,
-        handle_response_body, parse_amount_query, parse_comment_query, parse_name_query,
-        parse_nostr_query,
+        convert_key, find_key, get_description, get_digest, handle_bad_request, handle_ok_request,
+        handle_response_body, handle_unauthorized_request, handle_unavailable_request,
+        parse_amount_query, parse_comment_query, parse_name_query, parse_nostr_query,
+        parse_payer_data_query,
     },
-    utils::{create_invoice, get_identifiers},
+    success_action::{SuccessActionContext, get_success_action},
+    utils::{create_invoice, get_identifiers, get_live_invoice_policy},
+    vouchers::{NewBatch, create_batch, get_withdraw_request, list_batches, start_withdrawal},
+    zap_receipts::{PayerRequest, get_issued_invoice},
 };
-use crate::config::get_config;
+use crate::{
+    config::{BackendKind, get_config, is_demo_mode},
+    credentials::{
+        get_mock::get_mock,
+        lightning_backend::{
+            InvoiceError, get_backend_kind, get_named_backend, get_user_backend_name,
+            is_backend_ready,
+        },
+    },
+};
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(15);
 
 pub async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let method = req.method();
@@ -25,16 +44,68 @@ pub async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper:
             handle_default_path()
         }
 
-        (&hyper::Method::GET, "/health") => {
+        (&hyper::Method::GET, "/health") | (&hyper::Method::GET, "/health/live") => {
             debug!(target: "server::handle_request", "Handling health check request");
             handle_health_path()
         }
 
+        (&hyper::Method::GET, "/health/ready") => {
+            debug!(target: "server::handle_request", "Handling readiness check request");
+            handle_readiness_path().await
+        }
+
         (&hyper::Method::GET, path) if path.starts_with("/.well-known/lnurlp/") => {
             debug!(target: "server::handle_request", "Handling LNURL payment request for path: {}", path);
             handle_invoice_path(path, req.uri()).await
         }
 
+        (&hyper::Method::GET, path)
+            if path.starts_with("/lnurlp/") && path.contains("/verify/") =>
+        {
+            debug!(target: "server::handle_request", "Handling LUD-21 verify request for path: {}", path);
+            handle_verify_path(path).await
+        }
+
+        (&hyper::Method::GET, path) if path.starts_with("/lnurlw/") => {
+            debug!(target: "server::handle_request", "Handling LNURL withdraw request for path: {}", path);
+            handle_withdraw_path(path, req.uri())
+        }
+
+        (&hyper::Method::GET, "/admin/login") => {
+            debug!(target: "server::handle_request", "Handling admin login request");
+            handle_admin_login_path()
+        }
+
+        (&hyper::Method::GET, "/admin/login/callback") => {
+            debug!(target: "server::handle_request", "Handling admin login callback");
+            handle_admin_login_callback_path(req.uri())
+        }
+
+        (&hyper::Method::GET, "/admin/session") => {
+            debug!(target: "server::handle_request", "Handling admin session request");
+            handle_admin_session_path(&req)
+        }
+
+        (&hyper::Method::POST, "/admin/logout") => {
+            debug!(target: "server::handle_request", "Handling admin logout request");
+            handle_admin_logout_path(&req)
+        }
+
+        (&hyper::Method::GET, "/admin/vouchers") => {
+            debug!(target: "server::handle_request", "Handling voucher list request");
+            handle_list_vouchers_path(&req)
+        }
+
+        (&hyper::Method::POST, "/admin/vouchers") => {
+            debug!(target: "server::handle_request", "Handling voucher creation request");
+            handle_create_vouchers_path(req).await
+        }
+
+        (&hyper::Method::POST, path) if path.starts_with("/admin/mock/settle/") => {
+            debug!(target: "server::handle_request", "Handling mock settle request for path: {}", path);
+            handle_mock_settle_path(&req, path)
+        }
+
         (&hyper::Method::GET, path) if path.starts_with("/.well-known/nostr.json") => {
             debug!(target: "server::handle_request", "Handling NIP-05 verification request");
             handle_nip05_path(req.uri()).await
@@ -77,6 +148,26 @@ fn handle_health_path() -> Result<Response<Body>, hyper::Error> {
     handle_ok_request(response_body_string)
 }
 
+/// Reports whether invoices can be served right now. Answers 503 when a critical check fails.
+async fn handle_readiness_path() -> Result<Response<Body>, hyper::Error> {
+    debug!(target: "server::handle_request::health", "Readiness check requested");
+    let readiness = check_readiness().await;
+
+    let response_body_string = match serde_json::to_string(&readiness) {
+        Ok(body) => body,
+        Err(e) => {
+            error!(target: "server::handle_request::health", "Failed to serialize readiness response: {}", e);
+            return handle_bad_request("Internal Server Error");
+        }
+    };
+
+    if readiness.is_ready() {
+        handle_ok_request(response_body_string)
+    } else {
+        handle_unavailable_request(response_body_string)
+    }
+}
+
 fn handle_default_path() -> Result<Response<Body>, hyper::Error> {
     let (domain, username) = get_identifiers(None);
     debug!(target: "server::handle_request::default", "Using domain: {}, username: {}", domain, username);
@@ -140,19 +231,20 @@ struct SuccessPathResponse {
     pr: String,
     routes: Vec<String>,
     status: String,
-    success_action: SuccessAction,
-}
-
-#[derive(Serialize, Deserialize)]
-struct SuccessAction {
-    tag: String,
-    message: String,
+    success_action: Value,
+    verify: String,
 }
 
 async fn handle_invoice_path(path: &str, uri: &Uri) -> Result<Response<Body>, hyper::Error> {
     info!(target: "server::handle_request::invoice", "Processing invoice request for path: {}", path);
     let username = path.rsplit('/').next();
-    let response_body_string = handle_response_body(username);
+
+    // Users on their own node do not wait for the default one.
+    let (_, resolved) = get_identifiers(username);
+    if !is_backend_ready(get_user_backend_name(&resolved).as_deref()) {
+        return handle_bad_request("LightningBackendNotReady");
+    }
+    let response_body_string = handle_response_body(username).await;
 
     info!(target: "server::handle_request::invoice", "Checking username: {:?}", username);
 
@@ -174,25 +266,33 @@ async fn handle_invoice_path(path: &str, uri: &Uri) -> Result<Response<Body>, hy
                 let amount_key = find_key("amount", &query_pairs);
                 let comment_key = find_key("comment", &query_pairs);
                 let nostr_key = find_key("nostr", &query_pairs);
+                let payer_data_key = find_key("payerdata", &query_pairs);
+                let route_hints_only = find_key("blinded", &query_pairs)
+                    .is_some_and(|(_, value)| value == "0" || value == "false");
 
                 let parsed_nostr_query = parse_nostr_query(nostr_key.cloned());
                 debug!(target: "server::handle_request::invoice", "Parsed nostr query: {:?}", parsed_nostr_query);
 
-                let digest = get_digest(parsed_nostr_query.as_ref().ok(), Some(name));
-
-                let amount = match parse_amount_query(amount_key.cloned()) {
+                let policy = get_live_invoice_policy(Some(name)).await;
+                let (amount, fiat) = match parse_amount_query(amount_key.cloned(), &policy).await {
                     Ok(a) => a,
                     Err(e) => {
                         error!(target: "server::handle_request::invoice", "Failed to parse amount: {:?}", e);
-                        return handle_bad_request("UnableToParseAmount");
+                        // Conversion failures tell the payer to pick another currency or retry.
+                        return match e.as_str() {
+                            "UnsupportedCurrency" | "PriceUnavailable" | "PriceTooOld" => {
+                                handle_bad_request(&e)
+                            }
+                            _ => handle_bad_request("UnableToParseAmount"),
+                        };
                     }
                 };
 
-                let comment = match parse_comment_query(comment_key.cloned()) {
+                let comment = match parse_comment_query(comment_key.cloned(), &policy) {
                     Ok(c) => c,
                     Err(e) => {
                         error!(target: "server::handle_request::invoice", "Failed to parse comment: {:?}", e);
-                        return handle_bad_request("FailedToParseComments");
+                        return handle_bad_request(&e);
                     }
                 };
 
@@ -201,19 +301,75 @@ async fn handle_invoice_path(path: &str, uri: &Uri) -> Result<Response<Body>, hy
                     return handle_ok_request(response_body_string);
                 }
 
+                let payer_data = match parse_payer_data_query(payer_data_key.cloned(), &policy) {
+                    Ok(payer_data) => payer_data,
+                    Err(e) => {
+                        error!(target: "server::handle_request::invoice", "Failed to parse payer data: {:?}", e);
+                        return handle_bad_request(&e);
+                    }
+                };
+                let payer_data_raw = payer_data.as_ref().map(|p| p.raw.as_str());
+
+                let description =
+                    get_description(parsed_nostr_query.as_ref().ok(), Some(name), payer_data_raw);
+                let digest =
+                    get_digest(parsed_nostr_query.as_ref().ok(), Some(name), payer_data_raw);
+
                 debug!(target: "server::handle_request::invoice", "Creating invoice for amount: {}, comment: {}", amount, comment);
-                let pr = create_invoice(digest, comment, amount, parsed_nostr_query).await;
-                debug!(target: "server::handle_request::invoice", "Created payment request: {}", pr);
+                let (invoice, preimage) = match create_invoice(
+                    name,
+                    digest,
+                    description,
+                    comment.clone(),
+                    amount,
+                    parsed_nostr_query,
+                    PayerRequest {
+                        payer_data: payer_data.map(|p| p.value),
+                        fiat,
+                        route_hints_only,
+                    },
+                )
+                .await
+                {
+                    Ok(invoice) => invoice,
+                    Err(e) => {
+                        warn!(target: "server::handle_request::invoice", "Failed to create invoice: {}", e);
+                        return handle_bad_request(e.reason());
+                    }
+                };
 
+                let context = SuccessActionContext {
+                    username: name,
+                    amount_msat: amount,
+                    payment_hash: &invoice.payment_hash,
+                    comment: &comment,
+                };
+                let success_action = match get_success_action(
+                    policy.success_action.as_ref(),
+                    &context,
+                    preimage.as_ref(),
+                ) {
+                    Ok(success_action) => success_action,
+                    Err(e) => {
+                        error!(target: "server::handle_request::invoice", "Failed to build success action: {}", e);
+                        return handle_bad_request(&e);
+                    }
+                };
+                debug!(target: "server::handle_request::invoice", "Created payment request: {}", invoice.payment_request);
+
+                let (domain, _) = get_identifiers(Some(name));
                 let success_response_body = SuccessPathResponse {
                     disposable: false,
-                    pr,
+                    pr: invoice.payment_request,
                     routes: vec![],
                     status: "OK".to_string(),
-                    success_action: SuccessAction {
-                        tag: "message".to_string(),
-                        message: "Payment received!".to_string(),
-                    },
+                    success_action,
+                    verify: format!(
+                        "https://{}/lnurlp/{}/verify/{}",
+                        domain,
+                        name,
+                        hex::encode(&invoice.payment_hash)
+                    ),
                 };
 
                 match serde_json::to_string(&success_response_body) {
@@ -238,6 +394,282 @@ async fn handle_invoice_path(path: &str, uri: &Uri) -> Result<Response<Body>, hy
     }
 }
 
+/// LUD-21: whether an invoice rustdress issued to the user has been paid, and its preimage once
+/// it has.
+async fn handle_verify_path(path: &str) -> Result<Response<Body>, hyper::Error> {
+    let mut segments = path.trim_start_matches("/lnurlp/").split('/');
+    let (username, payment_hash) = match (segments.next(), segments.next(), segments.next()) {
+        (Some(username), Some("verify"), Some(payment_hash)) if segments.next().is_none() => {
+            (username, payment_hash)
+        }
+        _ => {
+            warn!(target: "server::handle_request::verify", "Invalid verify path: {}", path);
+            return handle_unknown_path();
+        }
+    };
+
+    let payment_hash = match hex::decode(payment_hash) {
+        Ok(hash) if hash.len() == 32 => hash,
+        _ => {
+            warn!(target: "server::handle_request::verify", "Invalid payment hash in path: {}", path);
+            return handle_bad_request("InvalidPaymentHash");
+        }
+    };
+
+    let issued = match get_issued_invoice(&payment_hash) {
+        Some(issued) if issued.username == username => issued,
+        _ => {
+            warn!(target: "server::handle_request::verify", "Verify requested for an invoice not issued to {}", username);
+            return handle_bad_request("InvoiceNotFound");
+        }
+    };
+
+    let backend = get_named_backend(issued.backend.as_deref());
+    let invoice = match timeout(VERIFY_TIMEOUT, backend.lookup_invoice(&payment_hash)).await {
+        Ok(Ok(invoice)) => invoice,
+        Ok(Err(e)) => {
+            warn!(target: "server::handle_request::verify", "Failed to look up invoice: {}", e);
+            return handle_bad_request(InvoiceError::from_backend(e).reason());
+        }
+        Err(_) => {
+            warn!(target: "server::handle_request::verify", "Timed out looking up invoice");
+            return handle_bad_request(InvoiceError::NodeOffline.reason());
+        }
+    };
+    debug!(target: "server::handle_request::verify", "Invoice {} settled: {}", hex::encode(&payment_hash), invoice.settled);
+
+    let response_body = json!({
+        "status": "OK",
+        "settled": invoice.settled,
+        "preimage": invoice.preimage.map(hex::encode),
+        "pr": invoice.payment_request,
+    });
+
+    handle_ok_request(response_body.to_string())
+}
+
+/// LUD-03: the withdraw request for a voucher, and its callback paying the wallet's invoice.
+fn handle_withdraw_path(path: &str, uri: &Uri) -> Result<Response<Body>, hyper::Error> {
+    let mut segments = path.trim_start_matches("/lnurlw/").split('/');
+    let (id, is_callback) = match (segments.next(), segments.next(), segments.next()) {
+        (Some(id), None, None) if !id.is_empty() => (id, false),
+        (Some(id), Some("callback"), None) if !id.is_empty() => (id, true),
+        _ => {
+            warn!(target: "server::handle_request::withdraw", "Invalid withdraw path: {}", path);
+            return handle_unknown_path();
+        }
+    };
+
+    if !is_callback {
+        return match get_withdraw_request(id) {
+            Ok(withdraw_request) => handle_ok_request(withdraw_request.to_string()),
+            Err(e) => {
+                warn!(target: "server::handle_request::withdraw", "Refused withdraw request for voucher {}: {}", id, e);
+                handle_bad_request(&e)
+            }
+        };
+    }
+
+    if !is_backend_ready(None) {
+        return handle_bad_request("LightningBackendNotReady");
+    }
+
+    let query_pairs: Vec<(String, String)> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .map(|kv| {
+            let mut iter = kv.split('=');
+            let key = iter.next().unwrap().to_string();
+            let value = iter.next().unwrap_or("").to_string();
+            (key, value)
+        })
+        .collect();
+
+    let (k1, payment_request) = match (find_key("k1", &query_pairs), find_key("pr", &query_pairs)) {
+        (Some((_, k1)), Some((_, pr))) => (k1, pr),
+        _ => {
+            warn!(target: "server::handle_request::withdraw", "Withdraw callback without k1 or pr");
+            return handle_bad_request("MissingK1OrPaymentRequest");
+        }
+    };
+
+    match start_withdrawal(id, k1, payment_request) {
+        Ok(()) => {
+            info!(target: "server::handle_request::withdraw", "Paying out voucher {}", id);
+            handle_ok_request(json!({ "status": "OK" }).to_string())
+        }
+        Err(e) => {
+            warn!(target: "server::handle_request::withdraw", "Refused withdrawal of voucher {}: {}", id, e);
+            handle_bad_request(&e)
+        }
+    }
+}
+
+fn handle_admin_login_path() -> Result<Response<Body>, hyper::Error> {
+    match begin_login() {
+        Ok(login) => {
+            let mut response_body = login;
+            response_body["status"] = json!("OK");
+            handle_ok_request(response_body.to_string())
+        }
+        Err(e) => {
+            warn!(target: "server::handle_request::admin", "Failed to start admin login: {}", e);
+            handle_bad_request(&e)
+        }
+    }
+}
+
+/// LUD-04: the wallet's signature of a login challenge.
+fn handle_admin_login_callback_path(uri: &Uri) -> Result<Response<Body>, hyper::Error> {
+    let query_pairs: Vec<(String, String)> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .map(|kv| {
+            let mut iter = kv.split('=');
+            let key = iter.next().unwrap().to_string();
+            let value = iter.next().unwrap_or("").to_string();
+            (key, value)
+        })
+        .collect();
+
+    let (k1, sig, key) = match (
+        find_key("k1", &query_pairs),
+        find_key("sig", &query_pairs),
+        find_key("key", &query_pairs),
+    ) {
+        (Some((_, k1)), Some((_, sig)), Some((_, key))) => (k1, sig, key),
+        _ => {
+            warn!(target: "server::handle_request::admin", "Login callback without k1, sig or key");
+            return handle_bad_request("MissingK1SigOrKey");
+        }
+    };
+
+    match complete_login(k1, sig, key) {
+        Ok(()) => handle_ok_request(json!({ "status": "OK" }).to_string()),
+        Err(e) => {
+            warn!(target: "server::handle_request::admin", "Refused admin login: {}", e);
+            handle_bad_request(&e)
+        }
+    }
+}
+
+/// The caller's signed-in session, so a client can wait for the wallet to sign.
+fn handle_admin_session_path(req: &Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    match get_session(req) {
+        Some(session) => {
+            let mut response_body = session;
+            response_body["status"] = json!("OK");
+            handle_ok_request(response_body.to_string())
+        }
+        None => handle_unauthorized_request(),
+    }
+}
+
+fn handle_admin_logout_path(req: &Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if !end_session(req) {
+        return handle_unauthorized_request();
+    }
+    info!(target: "server::handle_request::admin", "Admin signed out");
+
+    handle_ok_request(json!({ "status": "OK" }).to_string())
+}
+
+fn handle_list_vouchers_path(req: &Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if !is_admin(req) {
+        warn!(target: "server::handle_request::admin", "Unauthorized voucher list request");
+        return handle_unauthorized_request();
+    }
+
+    let response_body = json!({
+        "status": "OK",
+        "batches": list_batches(),
+    });
+
+    handle_ok_request(response_body.to_string())
+}
+
+/// Creates a voucher batch from a JSON body with `amount_msat`, `uses`, `expiry_secs` and an
+/// optional `description`.
+async fn handle_create_vouchers_path(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if !is_admin(&req) {
+        warn!(target: "server::handle_request::admin", "Unauthorized voucher creation request");
+        return handle_unauthorized_request();
+    }
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(target: "server::handle_request::admin", "Failed to read request body: {}", e);
+            return handle_bad_request("FailedToReadBody");
+        }
+    };
+
+    let request: NewBatch = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!(target: "server::handle_request::admin", "Failed to parse voucher batch: {}", e);
+            return handle_bad_request("InvalidVoucherBatch");
+        }
+    };
+
+    match create_batch(request) {
+        Ok(batch) => {
+            let response_body = json!({
+                "status": "OK",
+                "batch": batch,
+            });
+
+            handle_ok_request(response_body.to_string())
+        }
+        Err(e) => handle_bad_request(&e),
+    }
+}
+
+/// Marks a mock invoice paid. Open to anyone with `--demo`, which exists to try rustdress out,
+/// and for admins only when the mock backend is configured.
+fn handle_mock_settle_path(
+    req: &Request<Body>,
+    path: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    if get_backend_kind() != BackendKind::Mock {
+        warn!(target: "server::handle_request::mock", "Mock settle requested without the mock backend");
+        return handle_unknown_path();
+    }
+
+    if !is_demo_mode() && !is_admin(req) {
+        warn!(target: "server::handle_request::mock", "Unauthorized mock settle request");
+        return handle_unauthorized_request();
+    }
+
+    let payment_hash = match path.rsplit('/').next().map(hex::decode) {
+        Some(Ok(hash)) if hash.len() == 32 => hash,
+        _ => {
+            warn!(target: "server::handle_request::mock", "Invalid payment hash in path: {}", path);
+            return handle_bad_request("InvalidPaymentHash");
+        }
+    };
+
+    match get_mock().settle_invoice(&payment_hash) {
+        Ok(invoice) => {
+            info!(target: "server::handle_request::mock", "Settled mock invoice {}", hex::encode(&payment_hash));
+            let response_body = json!({
+                "status": "OK",
+                "payment_hash": hex::encode(&payment_hash),
+                "preimage": hex::encode(invoice.preimage),
+                "pr": invoice.payment_request,
+            });
+
+            handle_ok_request(response_body.to_string())
+        }
+        Err(e) => {
+            warn!(target: "server::handle_request::mock", "Failed to settle mock invoice: {}", e);
+            handle_bad_request("InvoiceNotFound")
+        }
+    }
+}
+
 async fn handle_nip05_path(uri: &Uri) -> Result<Response<Body>, hyper::Error> {
     info!(target: "server::handle_request::nip05", "Processing NIP-05 verification request");
 
@@ -0,0 +1,262 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::{
+    credentials::{
+        lightning_backend::{get_backend, is_backend_ready},
+        macaroon_audit::is_permission_denied,
+        reload::{CredentialReload, get_last_reloads},
+    },
+    server::{publish_to_relay::check_relay, utils::get_relays},
+};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const RELAY_CHECK_TTL: Duration = Duration::from_secs(60);
+
+static LAST_RELAY_CHECK: Lazy<Mutex<Option<RelayCheck>>> = Lazy::new(|| Mutex::new(None));
+
+static RELAY_CHECK_RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    /// Degraded, but invoices can still be created.
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// Failures of critical checks make the instance unready.
+    pub critical: bool,
+    pub latency_ms: u128,
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: Vec<Check>,
+    /// The latest reload of each backend, or failover node, whose cert or macaroon file changed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub credential_reloads: Vec<CredentialReload>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == "OK"
+    }
+}
+
+fn check(
+    name: &'static str,
+    critical: bool,
+    started: Instant,
+    result: Result<String, String>,
+    failure: CheckStatus,
+) -> Check {
+    let (status, detail) = match result {
+        Ok(detail) => (CheckStatus::Ok, detail),
+        Err(detail) => (failure, detail),
+    };
+
+    Check {
+        name,
+        status,
+        critical,
+        latency_ms: started.elapsed().as_millis(),
+        detail,
+    }
+}
+
+/// How many relays could be reached, and when that was checked.
+#[derive(Clone, Copy)]
+struct RelayCheck {
+    connected: usize,
+    total: usize,
+    checked_at: Instant,
+}
+
+/// The last relay check. Relays are third parties, so probes never wait on them: when the
+/// result is older than `RELAY_CHECK_TTL` a new check starts in the background.
+fn last_relay_check() -> Option<RelayCheck> {
+    let last = *LAST_RELAY_CHECK.lock().expect("relay check lock poisoned");
+
+    let stale = last.is_none_or(|last| last.checked_at.elapsed() >= RELAY_CHECK_TTL);
+    if stale && !RELAY_CHECK_RUNNING.swap(true, Ordering::SeqCst) {
+        tokio::spawn(async {
+            let checked = check_relays().await;
+            *LAST_RELAY_CHECK.lock().expect("relay check lock poisoned") = Some(checked);
+            RELAY_CHECK_RUNNING.store(false, Ordering::SeqCst);
+        });
+    }
+
+    last
+}
+
+async fn check_relays() -> RelayCheck {
+    let relays = get_relays(None);
+    let results = join_all(
+        relays
+            .iter()
+            .map(|relay| timeout(CHECK_TIMEOUT, check_relay(relay))),
+    )
+    .await;
+
+    RelayCheck {
+        connected: results
+            .iter()
+            .filter(|result| matches!(result, Ok(Ok(()))))
+            .count(),
+        total: relays.len(),
+        checked_at: Instant::now(),
+    }
+}
+
+/// Checks the default backend and reports the relays. Only the backend being reachable,
+/// through startup and synced to chain is critical; the rest is reported as a warning.
+pub async fn check_readiness() -> Readiness {
+    let backend = get_backend();
+    let mut checks = vec![];
+
+    let started = Instant::now();
+    checks.push(check(
+        "startup",
+        true,
+        started,
+        if is_backend_ready(None) {
+            Ok("Invoice test passed".to_string())
+        } else {
+            Err("Waiting for the Lightning node".to_string())
+        },
+        CheckStatus::Fail,
+    ));
+
+    let started = Instant::now();
+    match timeout(CHECK_TIMEOUT, backend.get_node_info()).await {
+        Ok(Ok(node)) => {
+            checks.push(check(
+                "backend",
+                true,
+                started,
+                Ok(format!(
+                    "{} ({}) running {}",
+                    node.alias, node.pubkey, node.version
+                )),
+                CheckStatus::Fail,
+            ));
+            checks.push(check(
+                "synced_to_chain",
+                true,
+                started,
+                if node.synced_to_chain {
+                    Ok(format!("Block height {}", node.block_height))
+                } else {
+                    Err(format!("Not synced at block height {}", node.block_height))
+                },
+                CheckStatus::Fail,
+            ));
+            // Wallet services such as NWC cannot report channels, so there is nothing to check.
+            if let Some(num_active_channels) = node.num_active_channels {
+                checks.push(check(
+                    "active_channels",
+                    false,
+                    started,
+                    if num_active_channels > 0 {
+                        Ok(num_active_channels.to_string())
+                    } else {
+                        Err("No active channels".to_string())
+                    },
+                    CheckStatus::Warn,
+                ));
+            }
+        }
+        // A macaroon limited to invoices cannot read node info, but the node answered.
+        Ok(Err(e)) if is_permission_denied(&e) => checks.push(check(
+            "backend",
+            true,
+            started,
+            Ok("Reachable, but the macaroon cannot read node info".to_string()),
+            CheckStatus::Fail,
+        )),
+        Ok(Err(e)) => checks.push(check(
+            "backend",
+            true,
+            started,
+            Err(e.to_string()),
+            CheckStatus::Fail,
+        )),
+        Err(_) => checks.push(check(
+            "backend",
+            true,
+            started,
+            Err("Timed out".to_string()),
+            CheckStatus::Fail,
+        )),
+    }
+
+    let started = Instant::now();
+    let inbound = match timeout(CHECK_TIMEOUT, backend.get_inbound_capacity()).await {
+        Ok(Ok(Some(capacity))) if capacity > 0 => Ok(format!("{} msat", capacity)),
+        Ok(Ok(Some(_))) => Err("No inbound capacity".to_string()),
+        Ok(Ok(None)) => Ok("Not reported by this backend".to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("Timed out".to_string()),
+    };
+    checks.push(check(
+        "inbound_capacity",
+        false,
+        started,
+        inbound,
+        CheckStatus::Warn,
+    ));
+
+    let started = Instant::now();
+    checks.push(check(
+        "relays",
+        false,
+        started,
+        match last_relay_check() {
+            Some(relays) if relays.connected > 0 => Ok(format!(
+                "{}/{} relays reachable",
+                relays.connected, relays.total
+            )),
+            Some(relays) => Err(format!("0/{} relays reachable", relays.total)),
+            None => Ok("Not checked yet".to_string()),
+        },
+        CheckStatus::Warn,
+    ));
+
+    let ready = !checks
+        .iter()
+        .any(|check| check.critical && check.status == CheckStatus::Fail);
+
+    for check in checks
+        .iter()
+        .filter(|check| check.status != CheckStatus::Ok)
+    {
+        warn!(target: "server::health", "Readiness check {} failed: {}", check.name, check.detail);
+    }
+    debug!(target: "server::health", "Readiness: {}", ready);
+
+    Readiness {
+        status: if ready { "OK" } else { "ERROR" },
+        checks,
+        credential_reloads: get_last_reloads(),
+    }
+}
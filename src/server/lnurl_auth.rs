@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use secp256k1::{Message, PublicKey, Secp256k1, ecdsa::Signature};
+use tracing::{debug, warn};
+
+/// How long a challenge can be signed after it is handed out.
+const K1_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Every pay request hands out a challenge, so only this many are kept; beyond that the
+/// oldest is dropped.
+const MAX_CHALLENGES: usize = 10_000;
+
+/// Outstanding LNURL-auth challenges by hex k1, with when they were handed out.
+static CHALLENGES: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A fresh hex encoded challenge for a wallet to sign, valid for `K1_TTL` and only once.
+pub fn issue_k1() -> String {
+    let k1 = hex::encode(rand::random::<[u8; 32]>());
+
+    let mut challenges = CHALLENGES.lock().expect("lnurl auth lock poisoned");
+    if challenges.len() >= MAX_CHALLENGES {
+        challenges.retain(|_, issued_at| issued_at.elapsed() < K1_TTL);
+    }
+    if challenges.len() >= MAX_CHALLENGES {
+        let oldest = challenges
+            .iter()
+            .min_by_key(|(_, issued_at)| **issued_at)
+            .map(|(k1, _)| k1.clone());
+        if let Some(oldest) = oldest {
+            challenges.remove(&oldest);
+        }
+    }
+    challenges.insert(k1.clone(), Instant::now());
+    debug!(target: "server::lnurl_auth", "Issued k1 {}", k1);
+
+    k1
+}
+
+/// Checks `sig`, a hex DER signature, is `key`'s signature of the challenge `k1`, and uses up
+/// the challenge. A bad signature leaves the challenge for its rightful signer.
+pub fn verify_k1(k1: &str, sig: &str, key: &str) -> Result<(), String> {
+    verify_signature(k1, sig, key)?;
+
+    let issued = CHALLENGES
+        .lock()
+        .expect("lnurl auth lock poisoned")
+        .remove(k1)
+        .is_some_and(|issued_at| issued_at.elapsed() < K1_TTL);
+
+    if !issued {
+        warn!(target: "server::lnurl_auth", "Unknown or expired k1 {}", k1);
+        return Err("UnknownOrExpiredK1".to_string());
+    }
+
+    Ok(())
+}
+
+/// Checks `sig`, a hex DER signature, is `key`'s signature of `k1`, without regard to whether
+/// the challenge was handed out.
+pub fn verify_signature(k1: &str, sig: &str, key: &str) -> Result<(), String> {
+    let message = hex::decode(k1)
+        .ok()
+        .and_then(|k1| Message::from_slice(&k1).ok())
+        .ok_or_else(|| "InvalidK1".to_string())?;
+    let key = hex::decode(key)
+        .ok()
+        .and_then(|key| PublicKey::from_slice(&key).ok())
+        .ok_or_else(|| "InvalidLinkingKey".to_string())?;
+    let mut signature = hex::decode(sig)
+        .ok()
+        .and_then(|sig| Signature::from_der(&sig).ok())
+        .ok_or_else(|| "InvalidSignature".to_string())?;
+
+    // Some wallets do not normalize their signatures to low S.
+    signature.normalize_s();
+
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, &signature, &key)
+        .map_err(|e| {
+            warn!(target: "server::lnurl_auth", "Signature check failed for {}: {}", key, e);
+            "InvalidSignature".to_string()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A LUD-04 login signed with a throwaway linking key.
+    const KEY: &str = "02e10e6188de29e212ef7d74644b9822d403283127432fa115bf3cbde61aead0dd";
+    const K1: &str = "6ab9f1eb8f7d3388f4f9d586f66e99fd54080df2c446f0e58668b09c08a16dd0";
+    const SIG: &str = "3044022017f53289eac961e5adc858d3ca50dab056ddca7a1a906c0815a0369312d1aa49022061f0bcd55129f1721020171a9f64844b11bd58def5bd990dbd906c4cc2b4ba41";
+    /// `SIG` with S replaced by N - S.
+    const HIGH_S_SIG: &str = "3045022017f53289eac961e5adc858d3ca50dab056ddca7a1a906c0815a0369312d1aa490221009e0f432aaed60e8defdfe8e5609b7bb3a8f18407b98b072e0241f2400d818700";
+
+    #[test]
+    fn accepts_valid_signature() {
+        assert_eq!(verify_signature(K1, SIG, KEY), Ok(()));
+    }
+
+    #[test]
+    fn refuses_tampered_signature_or_challenge() {
+        let tampered_sig = SIG.replace("61f0bcd5", "61f0bcd6");
+        assert_eq!(
+            verify_signature(K1, &tampered_sig, KEY),
+            Err("InvalidSignature".to_string())
+        );
+
+        let tampered_k1 = K1.replace("6ab9", "6ab8");
+        assert_eq!(
+            verify_signature(&tampered_k1, SIG, KEY),
+            Err("InvalidSignature".to_string())
+        );
+    }
+
+    #[test]
+    fn accepts_high_s_signature() {
+        assert_eq!(verify_signature(K1, HIGH_S_SIG, KEY), Ok(()));
+    }
+
+    #[test]
+    fn refuses_malformed_input() {
+        assert_eq!(
+            verify_signature("00", SIG, KEY),
+            Err("InvalidK1".to_string())
+        );
+        assert_eq!(
+            verify_signature(K1, SIG, "02"),
+            Err("InvalidLinkingKey".to_string())
+        );
+        assert_eq!(
+            verify_signature(K1, "3044", KEY),
+            Err("InvalidSignature".to_string())
+        );
+    }
+
+    #[test]
+    fn uses_up_challenges() {
+        let k1 = issue_k1();
+        assert_eq!(
+            verify_k1(&k1, SIG, KEY),
+            Err("InvalidSignature".to_string())
+        );
+        assert!(CHALLENGES.lock().unwrap().contains_key(&k1));
+
+        CHALLENGES
+            .lock()
+            .unwrap()
+            .insert(K1.to_string(), Instant::now());
+        let tampered_sig = SIG.replace("61f0bcd5", "61f0bcd6");
+        assert_eq!(
+            verify_k1(K1, &tampered_sig, KEY),
+            Err("InvalidSignature".to_string())
+        );
+        assert_eq!(verify_k1(K1, SIG, KEY), Ok(()));
+        assert_eq!(
+            verify_k1(K1, SIG, KEY),
+            Err("UnknownOrExpiredK1".to_string())
+        );
+
+        CHALLENGES
+            .lock()
+            .unwrap()
+            .insert(K1.to_string(), Instant::now() - K1_TTL);
+        assert_eq!(
+            verify_k1(K1, SIG, KEY),
+            Err("UnknownOrExpiredK1".to_string())
+        );
+    }
+}
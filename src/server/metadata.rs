@@ -0,0 +1,125 @@
+use std::{collections::HashMap, fs, io::Cursor};
+
+use anyhow::anyhow;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use image::{ImageFormat, ImageOutputFormat};
+use once_cell::sync::OnceCell;
+use tracing::{debug, info};
+
+use crate::{config::get_config, server::utils::get_identifiers};
+
+/// Larger files are refused rather than decoded.
+const MAX_AVATAR_FILE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Avatars are scaled down to fit a square of this many pixels, keeping the metadata (and so
+/// every pay request) small.
+const MAX_AVATAR_DIMENSION: u32 = 256;
+
+const AVATAR_JPEG_QUALITY: u8 = 85;
+
+/// Longest base64 avatar put in the metadata, which every pay request carries.
+const MAX_AVATAR_BASE64_BYTES: usize = 64 * 1024;
+
+/// Each user's avatar as a LUD-06 metadata entry, loaded once at startup.
+static AVATARS: OnceCell<HashMap<String, Avatar>> = OnceCell::new();
+
+struct Avatar {
+    mime_type: &'static str,
+    base64: String,
+}
+
+fn load_avatar(path: &str) -> Result<Avatar, anyhow::Error> {
+    let bytes = fs::read(path)?;
+    if bytes.len() > MAX_AVATAR_FILE_BYTES {
+        return Err(anyhow!(
+            "AvatarFileTooLarge: {} bytes, at most {}",
+            bytes.len(),
+            MAX_AVATAR_FILE_BYTES
+        ));
+    }
+
+    let format = image::guess_format(&bytes)?;
+    let (mime_type, output_format) = match format {
+        ImageFormat::Png => ("image/png;base64", ImageOutputFormat::Png),
+        ImageFormat::Jpeg => (
+            "image/jpeg;base64",
+            ImageOutputFormat::Jpeg(AVATAR_JPEG_QUALITY),
+        ),
+        _ => return Err(anyhow!("UnsupportedAvatarFormat: {:?}", format)),
+    };
+
+    // Always re-encoded, which also drops EXIF and other metadata the file may carry.
+    let mut image = image::load_from_memory_with_format(&bytes, format)?;
+    if image.width() > MAX_AVATAR_DIMENSION || image.height() > MAX_AVATAR_DIMENSION {
+        let scaled = image.thumbnail(MAX_AVATAR_DIMENSION, MAX_AVATAR_DIMENSION);
+        info!(target: "server::metadata", "Scaled avatar {} from {}x{} to {}x{}",
+            path, image.width(), image.height(), scaled.width(), scaled.height());
+        image = scaled;
+    }
+
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, output_format)?;
+    let base64 = STANDARD.encode(encoded.into_inner());
+
+    if base64.len() > MAX_AVATAR_BASE64_BYTES {
+        return Err(anyhow!(
+            "AvatarTooLarge: {} base64 bytes after scaling, at most {}",
+            base64.len(),
+            MAX_AVATAR_BASE64_BYTES
+        ));
+    }
+
+    Ok(Avatar { mime_type, base64 })
+}
+
+/// Reads and re-encodes every configured avatar. An avatar that cannot be used stops rustdress
+/// at startup.
+pub fn load_avatars() -> Result<(), anyhow::Error> {
+    let mut avatars = HashMap::new();
+
+    for user in &get_config().users {
+        let Some(path) = &user.avatar_path else {
+            continue;
+        };
+
+        let avatar = load_avatar(path).map_err(|e| {
+            anyhow!(
+                "FailedToLoadAvatar for {} from {}: {}",
+                user.username,
+                path,
+                e
+            )
+        })?;
+        info!(target: "server::metadata", "Loaded avatar for {} from {}", user.username, path);
+        avatars.insert(user.username.clone(), avatar);
+    }
+
+    debug!(target: "server::metadata", "Loaded {} avatars", avatars.len());
+    // A second call keeps the avatars already loaded.
+    let _ = AVATARS.set(avatars);
+
+    Ok(())
+}
+
+/// The LUD-06 metadata for `name`. Invoice description hashes commit to this exact string.
+pub fn get_metadata(name: Option<&str>) -> Result<String, serde_json::Error> {
+    let (domain, username) = get_identifiers(name);
+    let identifier = format!("{}@{}", username, domain);
+    let plain = format!("Paying satoshis to {}", identifier);
+
+    let mut metadata = vec![
+        ["text/identifier", identifier.as_str()],
+        ["text/plain", plain.as_str()],
+    ];
+
+    let user = get_config().users.iter().find(|u| u.username == username);
+    if let Some(long_description) = user.and_then(|u| u.long_description.as_ref()) {
+        metadata.push(["text/long-desc", long_description.as_str()]);
+    }
+
+    if let Some(avatar) = AVATARS.get().and_then(|avatars| avatars.get(&username)) {
+        metadata.push([avatar.mime_type, avatar.base64.as_str()]);
+    }
+
+    serde_json::to_string(&metadata)
+}
@@ -1,6 +1,15 @@
+pub mod admin;
 pub mod constants;
+pub mod encryption;
 pub mod handle_request;
+pub mod health;
+pub mod lnurl_auth;
+pub mod metadata;
 pub mod parsing_functions;
+pub mod price_source;
 pub mod publish_to_relay;
 pub mod start_server;
+pub mod success_action;
 pub mod utils;
+pub mod vouchers;
+pub mod zap_receipts;
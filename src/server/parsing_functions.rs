@@ -1,16 +1,20 @@
 use hyper::{Body, Response, StatusCode};
 use rusted_nostr_tools::{
-    event_methods::{get_event_hash, SignedEvent, UnsignedEvent},
     ConvertKey,
+    event_methods::{SignedEvent, UnsignedEvent, get_event_hash},
 };
-use serde_json::json;
+use serde_json::{Map, Value, json};
 use sha2::{Digest, Sha256};
 use tracing::{debug, error, warn};
 use urlencoding::decode;
 
-use crate::{config::get_config, server::constants::CONSTANTS};
-
-use super::utils::{get_identifiers, get_nostr_keys};
+use super::{
+    lnurl_auth::{issue_k1, verify_k1},
+    metadata::get_metadata,
+    price_source::{FiatConversion, convert_to_msat, get_currencies, get_currency_specs},
+    utils::{InvoicePolicy, get_identifiers, get_live_invoice_policy, get_nostr_keys},
+};
+use crate::config::PayerDataRequirement;
 
 pub fn find_key<'a>(key: &'a str, vector: &'a [(String, String)]) -> Option<&'a (String, String)> {
     debug!(target: "server::parsing", "Searching for key: {} in query parameters", key);
@@ -49,49 +53,105 @@ pub fn handle_ok_request(body: String) -> Result<Response<Body>, hyper::Error> {
     Ok(resp)
 }
 
-pub fn parse_amount_query(key: Option<(String, String)>) -> Result<i64, String> {
+pub fn handle_unauthorized_request() -> Result<Response<Body>, hyper::Error> {
+    warn!(target: "server::parsing", "Handling unauthorized request");
+    let response_body = json!({ "status": "ERROR", "reason": "Unauthorized" });
+
+    let resp = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("content-type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(response_body.to_string()))
+        .unwrap();
+    Ok(resp)
+}
+
+pub fn handle_unavailable_request(body: String) -> Result<Response<Body>, hyper::Error> {
+    warn!(target: "server::parsing", "Handling unavailable request");
+    let resp = Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("content-type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(body))
+        .unwrap();
+    Ok(resp)
+}
+
+/// Parses `amount` as millisatoshis, or as `<amount>.<code>` in the smallest unit of a
+/// configured currency, converted at its current price.
+pub async fn parse_amount_query(
+    key: Option<(String, String)>,
+    policy: &InvoicePolicy,
+) -> Result<(i64, Option<FiatConversion>), String> {
     match key {
         Some((_, amount)) => {
             if amount.is_empty() {
                 debug!(target: "server::parsing", "Empty amount provided, returning 0");
-                return Ok(0);
+                return Ok((0, None));
             }
 
-            let amount_str = amount.clone();
-            let amount = amount.parse::<i64>();
-
-            match amount {
-                Ok(a) => {
-                    if !(CONSTANTS.min_sendamount..=CONSTANTS.max_sendamount).contains(&a) {
-                        warn!(target: "server::parsing", "Amount {} is out of range [{}, {}]",
-                            a, CONSTANTS.min_sendamount, CONSTANTS.max_sendamount);
-                        return Err("AmountOutOfRange".to_string());
-                    }
+            let (amount_str, currency) = match amount.split_once('.') {
+                Some((amount, currency)) => (amount, Some(currency)),
+                None => (amount.as_str(), None),
+            };
 
-                    debug!(target: "server::parsing", "Successfully parsed amount: {}", a);
-                    Ok(a)
+            let a = match amount_str.parse::<i64>() {
+                Ok(a) => a,
+                Err(e) => {
+                    error!(target: "server::parsing", "Failed to parse amount '{}': {}", amount, e);
+                    return Err("FailedToParseAmount".to_string());
                 }
+            };
 
-                Err(e) => {
-                    error!(target: "server::parsing", "Failed to parse amount '{}': {}", amount_str, e);
-                    Err("FailedToParseAmount".to_string())
+            let (a, fiat) = match currency {
+                Some(currency) => {
+                    let (amount_msat, fiat) = convert_to_msat(currency, a).await?;
+                    (amount_msat, Some(fiat))
                 }
+                None => (a, None),
+            };
+
+            if !(policy.min_sendable_msat..=policy.max_sendable_msat).contains(&a) {
+                warn!(target: "server::parsing", "Amount {} is out of range [{}, {}]",
+                    a, policy.min_sendable_msat, policy.max_sendable_msat);
+                return Err("AmountOutOfRange".to_string());
             }
+
+            debug!(target: "server::parsing", "Successfully parsed amount: {}", a);
+            Ok((a, fiat))
         }
         None => {
             debug!(target: "server::parsing", "No amount provided, returning 0");
-            Ok(0)
+            Ok((0, None))
         }
     }
 }
 
-pub fn parse_comment_query(key: Option<(String, String)>) -> Result<String, String> {
+pub fn parse_comment_query(
+    key: Option<(String, String)>,
+    policy: &InvoicePolicy,
+) -> Result<String, String> {
     match key {
         Some((_, comment)) => {
-            if comment.len() > CONSTANTS.max_comment_length {
+            // Forms encode spaces as '+', and a literal '+' as "%2B".
+            let comment = match decode(&comment.replace('+', " ")) {
+                Ok(comment) => comment.into_owned(),
+                Err(e) => {
+                    error!(target: "server::parsing", "Failed to decode comment: {}", e);
+                    return Err("FailedToDecodeComment".to_string());
+                }
+            };
+
+            let length = comment.chars().count();
+            if length > policy.comment_allowed {
                 warn!(target: "server::parsing", "Comment length {} exceeds maximum {}",
-                    comment.len(), CONSTANTS.max_comment_length);
-                return Err("CommentCannotBeBlankOrGreaterThan50Characters".to_string());
+                    length, policy.comment_allowed);
+                return Err("CommentTooLong".to_string());
+            }
+
+            if comment.chars().any(char::is_control) {
+                warn!(target: "server::parsing", "Comment contains control characters");
+                return Err("CommentContainsControlCharacters".to_string());
             }
 
             debug!(target: "server::parsing", "Successfully parsed comment: {}", comment);
@@ -105,6 +165,107 @@ pub fn parse_comment_query(key: Option<(String, String)>) -> Result<String, Stri
     }
 }
 
+/// LUD-18 payer data, both as sent, which goes into the description hash, and parsed.
+pub struct PayerDataQuery {
+    pub raw: String,
+    pub value: Value,
+}
+
+fn is_internet_identifier(value: &str) -> bool {
+    value
+        .split_once('@')
+        .is_some_and(|(name, domain)| !name.is_empty() && domain.contains('.'))
+}
+
+/// Checks one payer field against LUD-18.
+fn validate_payer_field(field: &str, value: &Value) -> Result<(), String> {
+    let valid = match (field, value) {
+        ("name", Value::String(name)) => !name.is_empty(),
+        ("identifier", Value::String(identifier)) | ("email", Value::String(identifier)) => {
+            is_internet_identifier(identifier)
+        }
+        ("pubkey", Value::String(pubkey)) => {
+            hex::decode(pubkey).is_ok_and(|pubkey| pubkey.len() == 33)
+        }
+        ("auth", Value::Object(auth)) => {
+            let get = |key: &str| auth.get(key).and_then(Value::as_str).unwrap_or_default();
+            return verify_k1(get("k1"), get("sig"), get("key"));
+        }
+        _ => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        warn!(target: "server::parsing", "Invalid payer data field {}", field);
+        Err("InvalidPayerData".to_string())
+    }
+}
+
+pub fn parse_payer_data_query(
+    key: Option<(String, String)>,
+    policy: &InvoicePolicy,
+) -> Result<Option<PayerDataQuery>, String> {
+    let requested = policy.payer_data.clone().unwrap_or_default().fields();
+    let is_mandatory =
+        |field: &str| requested.contains(&(field, Some(PayerDataRequirement::Mandatory)));
+    let is_requested = |field: &str| {
+        requested
+            .iter()
+            .any(|(name, requirement)| *name == field && requirement.is_some())
+    };
+
+    let raw = match key {
+        Some((_, payer_data)) if !payer_data.is_empty() => match decode(&payer_data) {
+            Ok(raw) => raw.into_owned(),
+            Err(e) => {
+                error!(target: "server::parsing", "Failed to decode payer data: {}", e);
+                return Err("FailedToDecodePayerData".to_string());
+            }
+        },
+        _ => {
+            if requested.iter().any(|(field, _)| is_mandatory(field)) {
+                warn!(target: "server::parsing", "Mandatory payer data missing");
+                return Err("MissingMandatoryPayerData".to_string());
+            }
+
+            debug!(target: "server::parsing", "No payer data provided");
+            return Ok(None);
+        }
+    };
+
+    let fields = match serde_json::from_str::<Map<String, Value>>(&raw) {
+        Ok(fields) => fields,
+        Err(e) => {
+            error!(target: "server::parsing", "Failed to parse payer data: {}", e);
+            return Err("FailedToParsePayerData".to_string());
+        }
+    };
+
+    for (field, value) in &fields {
+        if !is_requested(field) {
+            warn!(target: "server::parsing", "Payer data field {} was not requested", field);
+            return Err("UnrequestedPayerData".to_string());
+        }
+
+        validate_payer_field(field, value)?;
+    }
+
+    if let Some((field, _)) = requested
+        .iter()
+        .find(|(field, _)| is_mandatory(field) && !fields.contains_key(*field))
+    {
+        warn!(target: "server::parsing", "Mandatory payer data field {} missing", field);
+        return Err("MissingMandatoryPayerData".to_string());
+    }
+
+    debug!(target: "server::parsing", "Successfully parsed payer data fields: {:?}", fields.keys());
+    Ok(Some(PayerDataQuery {
+        raw,
+        value: Value::Object(fields),
+    }))
+}
+
 pub fn parse_name_query(key: Option<(String, String)>) -> Result<String, String> {
     match key {
         Some((_, name)) => {
@@ -239,17 +400,11 @@ pub fn get_tags(tags: &[Vec<String>], key: &str) -> Option<Vec<String>> {
     }
 }
 
-pub fn handle_response_body(name: Option<&str>) -> String {
+pub async fn handle_response_body(name: Option<&str>) -> String {
     debug!(target: "server::parsing", "Generating response body for name: {:?}", name);
     let (domain, username) = get_identifiers(name);
 
-    let identifier = format!("{}@{}", username, domain);
-    debug!(target: "server::parsing", "Using identifier: {}", identifier);
-
-    let metadata = match serde_json::to_string(&[
-        ["text/identifier", &identifier],
-        ["text/plain", &format!("Paying satoshis to {}", identifier)],
-    ]) {
+    let metadata = match get_metadata(name) {
         Ok(metadata) => metadata,
         Err(e) => {
             error!(target: "server::parsing", "Failed to serialize metadata: {}", e);
@@ -259,15 +414,14 @@ pub fn handle_response_body(name: Option<&str>) -> String {
 
     let lnurl_url = "https://".to_owned() + &domain + "/.well-known/lnurlp/" + username.as_str();
 
-    let config = get_config();
-    let max_sendable_msat = config.max_sendable_msat.unwrap_or(CONSTANTS.max_sendamount);
+    let policy = get_live_invoice_policy(Some(&username)).await;
 
     let mut response_body = json!({
         "callback": lnurl_url,
-        "commentAllowed": CONSTANTS.max_comment_length,
-        "maxSendable": max_sendable_msat,
+        "commentAllowed": policy.comment_allowed,
+        "maxSendable": policy.max_sendable_msat,
         "metadata": metadata,
-        "minSendable": CONSTANTS.min_sendamount,
+        "minSendable": policy.min_sendable_msat,
         "tag": "payRequest",
         "status": "OK",
     });
@@ -280,6 +434,31 @@ pub fn handle_response_body(name: Option<&str>) -> String {
         }
     };
 
+    if let Some(payer_data) = &policy.payer_data {
+        let mut fields = Map::new();
+        for (field, requirement) in payer_data.fields() {
+            let Some(requirement) = requirement else {
+                continue;
+            };
+
+            let mut spec = json!({ "mandatory": requirement == PayerDataRequirement::Mandatory });
+            if field == "auth" {
+                spec["k1"] = Value::String(issue_k1());
+            }
+            fields.insert(field.to_string(), spec);
+        }
+
+        debug!(target: "server::parsing", "Requesting payer data: {:?}", fields.keys());
+        response_body["payerData"] = Value::Object(fields);
+    }
+
+    if !get_currencies().is_empty() {
+        let currencies =
+            get_currency_specs(policy.min_sendable_msat, policy.max_sendable_msat).await;
+        debug!(target: "server::parsing", "Offering {} currencies", currencies.len());
+        response_body["currencies"] = Value::Array(currencies);
+    }
+
     if !pubkey.is_empty() {
         debug!(target: "server::parsing", "Adding nostr pubkey to response: {}", pubkey);
         response_body["allowsNostr"] = serde_json::Value::Bool(true);
@@ -295,41 +474,163 @@ pub fn handle_response_body(name: Option<&str>) -> String {
     }
 }
 
-pub fn get_digest(nostr: Option<&SignedEvent>, name: Option<&str>) -> Vec<u8> {
-    debug!(target: "server::parsing", "Calculating digest for name: {:?}", name);
-    let mut hasher = Sha256::new();
-
-    let (domain, username) = get_identifiers(name);
-    let identifier = format!("{}@{}", username, domain);
-
-    let default_metadata = match serde_json::to_string(&[
-        ["text/identifier", &identifier],
-        ["text/plain", &format!("Paying satoshis to {}", identifier)],
-    ]) {
-        Ok(metadata) => metadata,
-        Err(e) => {
-            error!(target: "server::parsing", "Failed to serialize default metadata: {}", e);
-            "".to_string()
-        }
-    };
+/// The text committed to by an invoice's description hash: the zap request id for zaps,
+/// otherwise the LUD-06 metadata served for `name`, followed by the LUD-18 payer data as sent.
+pub fn get_description(
+    nostr: Option<&SignedEvent>,
+    name: Option<&str>,
+    payer_data: Option<&str>,
+) -> String {
+    debug!(target: "server::parsing", "Building invoice description for name: {:?}", name);
 
     match nostr {
         Some(event) => {
-            debug!(target: "server::parsing", "Using nostr event for digest calculation");
-            hasher.update(event.id.as_bytes());
-            hasher.finalize().to_vec()
+            debug!(target: "server::parsing", "Using nostr event for description");
+            event.id.clone()
         }
         None => {
-            debug!(target: "server::parsing", "Using default metadata for digest calculation");
-            hasher.update(default_metadata.as_bytes());
-            hasher.finalize().to_vec()
+            debug!(target: "server::parsing", "Using metadata for description");
+
+            match get_metadata(name) {
+                Ok(metadata) => metadata + payer_data.unwrap_or_default(),
+                Err(e) => {
+                    error!(target: "server::parsing", "Failed to serialize default metadata: {}", e);
+                    "".to_string()
+                }
+            }
         }
     }
 }
 
+pub fn get_digest(
+    nostr: Option<&SignedEvent>,
+    name: Option<&str>,
+    payer_data: Option<&str>,
+) -> Vec<u8> {
+    debug!(target: "server::parsing", "Calculating digest for name: {:?}", name);
+    let mut hasher = Sha256::new();
+    hasher.update(get_description(nostr, name, payer_data).as_bytes());
+    hasher.finalize().to_vec()
+}
+
 pub fn convert_key(key: &str) -> String {
     match ConvertKey::to_hex(key) {
         Ok(key) => key,
         Err(_) => key.to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_encoded_space_counts_as_one_character() {
+        let policy = InvoicePolicy {
+            min_sendable_msat: 1000,
+            max_sendable_msat: 1_000_000,
+            comment_allowed: 1,
+            expiry_secs: 3600,
+            blinded_paths: None,
+            payer_data: None,
+            success_action: None,
+        };
+        assert_eq!(
+            parse_comment_query(Some(("comment".to_string(), "%20".to_string())), &policy),
+            Ok(" ".to_string())
+        );
+        assert_eq!(
+            parse_comment_query(Some(("comment".to_string(), "a%20b".to_string())), &policy),
+            Err("CommentTooLong".to_string())
+        );
+    }
+
+    #[test]
+    fn emoji_counts_as_one_character() {
+        let policy = InvoicePolicy {
+            min_sendable_msat: 1000,
+            max_sendable_msat: 1_000_000,
+            comment_allowed: 1,
+            expiry_secs: 3600,
+            blinded_paths: None,
+            payer_data: None,
+            success_action: None,
+        };
+        assert_eq!(
+            parse_comment_query(
+                Some(("comment".to_string(), "%E2%9A%A1".to_string())),
+                &policy
+            ),
+            Ok("\u{26a1}".to_string())
+        );
+        assert_eq!(
+            parse_comment_query(
+                Some(("comment".to_string(), "\u{26a1}".to_string())),
+                &policy
+            ),
+            Ok("\u{26a1}".to_string())
+        );
+    }
+
+    #[test]
+    fn plus_decodes_to_a_space() {
+        let policy = InvoicePolicy {
+            min_sendable_msat: 1000,
+            max_sendable_msat: 1_000_000,
+            comment_allowed: 20,
+            expiry_secs: 3600,
+            blinded_paths: None,
+            payer_data: None,
+            success_action: None,
+        };
+        assert_eq!(
+            parse_comment_query(
+                Some(("comment".to_string(), "thanks+a+lot".to_string())),
+                &policy
+            ),
+            Ok("thanks a lot".to_string())
+        );
+        assert_eq!(
+            parse_comment_query(Some(("comment".to_string(), "1%2B1".to_string())), &policy),
+            Ok("1+1".to_string())
+        );
+    }
+
+    #[test]
+    fn control_characters_are_refused() {
+        let policy = InvoicePolicy {
+            min_sendable_msat: 1000,
+            max_sendable_msat: 1_000_000,
+            comment_allowed: 20,
+            expiry_secs: 3600,
+            blinded_paths: None,
+            payer_data: None,
+            success_action: None,
+        };
+        assert_eq!(
+            parse_comment_query(
+                Some(("comment".to_string(), "line%0Abreak".to_string())),
+                &policy
+            ),
+            Err("CommentContainsControlCharacters".to_string())
+        );
+        assert_eq!(
+            parse_comment_query(Some(("comment".to_string(), "%00".to_string())), &policy),
+            Err("CommentContainsControlCharacters".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_comment_is_empty() {
+        let policy = InvoicePolicy {
+            min_sendable_msat: 1000,
+            max_sendable_msat: 1_000_000,
+            comment_allowed: 0,
+            expiry_secs: 3600,
+            blinded_paths: None,
+            payer_data: None,
+            success_action: None,
+        };
+        assert_eq!(parse_comment_query(None, &policy), Ok("".to_string()));
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::config::{Currency, PriceSourceConfig, get_config};
+
+const MSAT_PER_BTC: f64 = 100_000_000_000.0;
+
+const DEFAULT_DECIMALS: u32 = 2;
+const DEFAULT_MAX_PRICE_AGE_SECS: u64 = 10 * 60;
+const DEFAULT_HTTP_REFRESH_SECS: u64 = 60;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Price sources by currency code, built once from the config.
+static PRICE_SOURCES: Lazy<HashMap<String, Arc<dyn PriceSource>>> = Lazy::new(|| {
+    get_currencies()
+        .iter()
+        .map(|currency| (currency.code.to_uppercase(), new_price_source(currency)))
+        .collect()
+});
+
+/// The price of one bitcoin in a currency's main unit, and when it was observed.
+#[derive(Debug, Clone, Copy)]
+pub struct Price {
+    pub btc_price: f64,
+    pub observed_at: i64,
+}
+
+/// Tells what a bitcoin is worth in one currency.
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    async fn get_price(&self) -> Result<Price, anyhow::Error>;
+}
+
+/// A fiat amount a payer asked for, and the price it was converted at.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FiatConversion {
+    pub currency: String,
+    /// In the currency's smallest unit.
+    pub amount: i64,
+    pub decimals: u32,
+    pub btc_price: f64,
+    pub price_observed_at: i64,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// The number at `pointer` in `document`, or the document itself without one. Feeds often
+/// send prices as strings, so numeric strings are accepted too.
+fn read_price(document: &Value, pointer: Option<&str>) -> Result<f64, anyhow::Error> {
+    let value = match pointer {
+        Some(pointer) => document
+            .pointer(pointer)
+            .ok_or_else(|| anyhow!("PriceNotFoundAt: {}", pointer))?,
+        None => document,
+    };
+
+    let price = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    };
+
+    price
+        .filter(|price| price.is_finite() && *price > 0.0)
+        .ok_or_else(|| anyhow!("InvalidPrice: {}", value))
+}
+
+struct StaticPrice {
+    btc_price: f64,
+}
+
+#[async_trait]
+impl PriceSource for StaticPrice {
+    async fn get_price(&self) -> Result<Price, anyhow::Error> {
+        Ok(Price {
+            btc_price: self.btc_price,
+            observed_at: now(),
+        })
+    }
+}
+
+/// Fetches the price at most every `refresh`, and keeps the last one for when the feed fails.
+struct HttpPrice {
+    client: Client,
+    url: String,
+    pointer: String,
+    refresh: Duration,
+    last: Mutex<Option<Price>>,
+}
+
+impl HttpPrice {
+    async fn fetch(&self) -> Result<Price, anyhow::Error> {
+        let response = self
+            .client
+            .get(&self.url)
+            .timeout(HTTP_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        let document: Value = response.json().await?;
+
+        Ok(Price {
+            btc_price: read_price(&document, Some(&self.pointer))?,
+            observed_at: now(),
+        })
+    }
+}
+
+#[async_trait]
+impl PriceSource for HttpPrice {
+    async fn get_price(&self) -> Result<Price, anyhow::Error> {
+        let mut last = self.last.lock().await;
+
+        if let Some(price) = *last
+            && now() - price.observed_at < self.refresh.as_secs() as i64
+        {
+            return Ok(price);
+        }
+
+        match self.fetch().await {
+            Ok(price) => {
+                debug!(target: "server::price_source", "Fetched price {} from {}", price.btc_price, self.url);
+                *last = Some(price);
+                Ok(price)
+            }
+            Err(e) => {
+                warn!(target: "server::price_source", "Failed to fetch price from {}: {}", self.url, e);
+                last.ok_or(e)
+            }
+        }
+    }
+}
+
+struct FilePrice {
+    path: String,
+    pointer: Option<String>,
+}
+
+#[async_trait]
+impl PriceSource for FilePrice {
+    async fn get_price(&self) -> Result<Price, anyhow::Error> {
+        let contents = fs::read_to_string(&self.path)?;
+        let observed_at = fs::metadata(&self.path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_secs() as i64;
+        let document: Value = serde_json::from_str(&contents)?;
+
+        Ok(Price {
+            btc_price: read_price(&document, self.pointer.as_deref())?,
+            observed_at,
+        })
+    }
+}
+
+fn new_price_source(currency: &Currency) -> Arc<dyn PriceSource> {
+    match &currency.price_source {
+        PriceSourceConfig::Static { price } => Arc::new(StaticPrice { btc_price: *price }),
+        PriceSourceConfig::Http {
+            url,
+            pointer,
+            refresh_secs,
+        } => Arc::new(HttpPrice {
+            client: Client::new(),
+            url: url.clone(),
+            pointer: pointer.clone(),
+            refresh: Duration::from_secs(refresh_secs.unwrap_or(DEFAULT_HTTP_REFRESH_SECS)),
+            last: Mutex::new(None),
+        }),
+        PriceSourceConfig::File { path, pointer } => Arc::new(FilePrice {
+            path: path.clone(),
+            pointer: pointer.clone(),
+        }),
+    }
+}
+
+pub fn get_currencies() -> &'static [Currency] {
+    get_config().currencies.as_deref().unwrap_or_default()
+}
+
+fn decimals(currency: &Currency) -> u32 {
+    currency.decimals.unwrap_or(DEFAULT_DECIMALS)
+}
+
+/// The currency's price, refused when it is older than the currency's `max_price_age_secs`.
+async fn get_fresh_price(currency: &Currency) -> Result<Price, String> {
+    let source = PRICE_SOURCES
+        .get(&currency.code.to_uppercase())
+        .ok_or_else(|| "UnsupportedCurrency".to_string())?;
+
+    let price = source.get_price().await.map_err(|e| {
+        warn!(target: "server::price_source", "No price for {}: {}", currency.code, e);
+        "PriceUnavailable".to_string()
+    })?;
+
+    if !price.btc_price.is_finite() || price.btc_price <= 0.0 {
+        warn!(target: "server::price_source", "Invalid price {} for {}", price.btc_price, currency.code);
+        return Err("PriceUnavailable".to_string());
+    }
+
+    let max_age = currency
+        .max_price_age_secs
+        .unwrap_or(DEFAULT_MAX_PRICE_AGE_SECS) as i64;
+    if now() - price.observed_at > max_age {
+        warn!(target: "server::price_source", "Price for {} is {}s old, over the {}s limit",
+            currency.code, now() - price.observed_at, max_age);
+        return Err("PriceTooOld".to_string());
+    }
+
+    Ok(price)
+}
+
+/// Millisatoshis per smallest unit of the currency.
+fn multiplier(currency: &Currency, price: &Price) -> f64 {
+    MSAT_PER_BTC / (price.btc_price * 10f64.powi(decimals(currency) as i32))
+}
+
+/// The `currencies` of a pay request, with amounts between `min_msat` and `max_msat`.
+/// Currencies without a fresh price are left out.
+pub async fn get_currency_specs(min_msat: i64, max_msat: i64) -> Vec<Value> {
+    let mut specs = vec![];
+
+    for currency in get_currencies() {
+        let Ok(price) = get_fresh_price(currency).await else {
+            continue;
+        };
+        let multiplier = multiplier(currency, &price);
+        let min = (min_msat as f64 / multiplier).ceil() as i64;
+        let max = (max_msat as f64 / multiplier).floor() as i64;
+
+        if min > max {
+            debug!(target: "server::price_source", "No {} amount fits between {} and {} msat", currency.code, min_msat, max_msat);
+            continue;
+        }
+
+        specs.push(json!({
+            "code": currency.code,
+            "name": currency.name,
+            "symbol": currency.symbol,
+            "decimals": decimals(currency),
+            "multiplier": multiplier,
+            "convertible": { "min": min, "max": max },
+        }));
+    }
+
+    specs
+}
+
+/// Converts `amount` in the smallest unit of the currency `code` to millisatoshis at the
+/// current price.
+pub async fn convert_to_msat(code: &str, amount: i64) -> Result<(i64, FiatConversion), String> {
+    let currency = get_currencies()
+        .iter()
+        .find(|currency| currency.code.eq_ignore_ascii_case(code))
+        .ok_or_else(|| "UnsupportedCurrency".to_string())?;
+    let price = get_fresh_price(currency).await?;
+
+    let amount_msat = (amount as f64 * multiplier(currency, &price)).round() as i64;
+    debug!(target: "server::price_source", "Converted {} {} to {} msat at {}", amount, currency.code, amount_msat, price.btc_price);
+
+    Ok((
+        amount_msat,
+        FiatConversion {
+            currency: currency.code.clone(),
+            amount,
+            decimals: decimals(currency),
+            btc_price: price.btc_price,
+            price_observed_at: price.observed_at,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_number_at_pointer() {
+        let document = json!({ "bitcoin": { "eur": 50000.5 } });
+        assert_eq!(
+            read_price(&document, Some("/bitcoin/eur")).ok(),
+            Some(50000.5)
+        );
+    }
+
+    #[test]
+    fn reads_numeric_string() {
+        let document = json!({ "data": { "amount": " 50000.25 " } });
+        assert_eq!(
+            read_price(&document, Some("/data/amount")).ok(),
+            Some(50000.25)
+        );
+    }
+
+    #[test]
+    fn reads_bare_document_without_pointer() {
+        assert_eq!(read_price(&json!(42000), None).ok(), Some(42000.0));
+        assert_eq!(read_price(&json!("42000"), None).ok(), Some(42000.0));
+    }
+
+    #[test]
+    fn refuses_missing_pointer() {
+        let document = json!({ "bitcoin": { "usd": 60000 } });
+        assert!(matches!(
+            read_price(&document, Some("/bitcoin/eur")),
+            Err(e) if e.to_string().starts_with("PriceNotFoundAt")
+        ));
+    }
+
+    #[test]
+    fn refuses_prices_that_are_not_positive_numbers() {
+        for value in [
+            json!(0),
+            json!(-1.5),
+            json!("NaN"),
+            json!("inf"),
+            json!("fifty"),
+            json!(true),
+            json!({ "eur": 50000 }),
+        ] {
+            assert!(matches!(
+                read_price(&value, None),
+                Err(e) if e.to_string().starts_with("InvalidPrice")
+            ));
+        }
+    }
+
+    #[test]
+    fn multiplier_is_msat_per_smallest_unit() {
+        // A cent at 50,000 EUR per bitcoin is 20 sats.
+        let currency = Currency {
+            code: "EUR".to_string(),
+            name: "Euro".to_string(),
+            symbol: "€".to_string(),
+            decimals: Some(2),
+            max_price_age_secs: None,
+            price_source: PriceSourceConfig::Static { price: 50_000.0 },
+        };
+        let price = Price {
+            btc_price: 50_000.0,
+            observed_at: 0,
+        };
+        assert_eq!(multiplier(&currency, &price), 20_000.0);
+
+        // A yen at 10,000,000 JPY per bitcoin is 10 sats.
+        let currency = Currency {
+            code: "JPY".to_string(),
+            name: "Japanese Yen".to_string(),
+            symbol: "¥".to_string(),
+            decimals: Some(0),
+            max_price_age_secs: None,
+            price_source: PriceSourceConfig::Static {
+                price: 10_000_000.0,
+            },
+        };
+        let price = Price {
+            btc_price: 10_000_000.0,
+            observed_at: 0,
+        };
+        assert_eq!(multiplier(&currency, &price), 10_000.0);
+    }
+
+    #[test]
+    fn multiplier_defaults_to_two_decimals() {
+        let currency = Currency {
+            code: "EUR".to_string(),
+            name: "Euro".to_string(),
+            symbol: "€".to_string(),
+            decimals: None,
+            max_price_age_secs: None,
+            price_source: PriceSourceConfig::Static { price: 50_000.0 },
+        };
+        let price = Price {
+            btc_price: 50_000.0,
+            observed_at: 0,
+        };
+        assert_eq!(multiplier(&currency, &price), 20_000.0);
+    }
+}
@@ -1,22 +1,27 @@
 use crate::server::{parsing_functions::get_tags, utils::get_nostr_keys};
-use futures::{future::join_all, SinkExt};
-use rusted_nostr_tools::event_methods::{get_event_hash, sign_event, SignedEvent, UnsignedEvent};
-use serde_json::json;
-use std::vec;
-use tokio_tungstenite::connect_async;
+use futures::{SinkExt, StreamExt, future::join_all};
+use rusted_nostr_tools::event_methods::{SignedEvent, UnsignedEvent, get_event_hash, sign_event};
+use serde_json::{Value, json};
+use std::{time::Duration, vec};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
 use tracing::{debug, error, info, warn};
 use tungstenite::Message as SocketMessage;
 
 use super::utils::get_relays;
 
-pub fn publish_zap_to_relays(
-    zap_request_json: SignedEvent,
+/// How long a relay has to answer a published event with `OK`.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The zap receipt (NIP-57) for a settled invoice, and the relays to publish it to.
+pub fn build_zap_receipt(
+    zap_request_json: &SignedEvent,
     comment: &str,
-    payment_request: String,
-    preimage: Vec<u8>,
+    payment_request: &str,
+    preimage: &[u8],
     settle_date: i64,
-) {
-    info!(target: "server::publish", "Publishing zap to relays");
+) -> Result<(Vec<String>, String), String> {
+    info!(target: "server::publish", "Building zap receipt");
     debug!(target: "server::publish", "Zap request content: {}", zap_request_json.content);
 
     let decoded_preimage = hex::encode(preimage);
@@ -24,15 +29,15 @@ pub fn publish_zap_to_relays(
         Ok(keys) => keys,
         Err(e) => {
             error!(target: "server::publish", "Failed to get nostr keys: {}", e);
-            return;
+            return Err("FailedToGetNostrKeys".to_string());
         }
     };
 
-    let zap_request_string = match serde_json::to_string::<SignedEvent>(&zap_request_json) {
+    let zap_request_string = match serde_json::to_string::<SignedEvent>(zap_request_json) {
         Ok(str) => str,
         Err(e) => {
             error!(target: "server::publish", "Failed to parse zap request for publishing to relays: {}", e);
-            return;
+            return Err("FailedToSerializeZapRequest".to_string());
         }
     };
 
@@ -40,7 +45,7 @@ pub fn publish_zap_to_relays(
         Some(r) => r,
         _ => {
             error!(target: "server::publish", "Failed to parse relay tags for publishing");
-            return;
+            return Err("FailedToParseRelayTags".to_string());
         }
     };
 
@@ -51,7 +56,7 @@ pub fn publish_zap_to_relays(
         Some(tags) => tags,
         _ => {
             error!(target: "server::publish", "Failed to parse e-tags for publishing");
-            return;
+            return Err("FailedToParseETags".to_string());
         }
     };
 
@@ -59,19 +64,19 @@ pub fn publish_zap_to_relays(
         Some(tags) => tags,
         _ => {
             error!(target: "server::publish", "Failed to parse p-tags for publishing");
-            return;
+            return Err("FailedToParsePTags".to_string());
         }
     };
 
     let ptags = vec!["p".to_string(), get_ptags[0].clone()];
     let etags = vec!["e".to_string(), get_etags[0].clone()];
-    let bolt11 = vec!["bolt11".to_string(), payment_request.clone()];
+    let bolt11 = vec!["bolt11".to_string(), payment_request.to_string()];
     let description = vec!["description".to_string(), zap_request_string.clone()];
     let payment_secret = vec!["preimage".to_string(), decoded_preimage.clone()];
 
     let content = if comment.is_empty() {
         debug!(target: "server::publish", "Using zap request content as no comment provided");
-        zap_request_json.content
+        zap_request_json.content.clone()
     } else {
         debug!(target: "server::publish", "Using provided comment: {}", comment);
         comment.to_string()
@@ -91,7 +96,7 @@ pub fn publish_zap_to_relays(
         Ok(id) => id,
         Err(e) => {
             error!(target: "server::publish", "Failed to calculate event hash for publishing: {}", e);
-            return;
+            return Err("FailedToHashEvent".to_string());
         }
     };
 
@@ -99,7 +104,7 @@ pub fn publish_zap_to_relays(
         Ok(sig) => sig,
         Err(e) => {
             error!(target: "server::publish", "Failed to sign event for publishing: {}", e);
-            return;
+            return Err("FailedToSignEvent".to_string());
         }
     };
 
@@ -119,17 +124,15 @@ pub fn publish_zap_to_relays(
         Ok(msg) => msg,
         Err(e) => {
             error!(target: "server::publish", "Failed to serialize zap note: {}", e);
-            return;
+            return Err("FailedToSerializeZapNote".to_string());
         }
     };
 
-    debug!(target: "server::publish", "Spawning task to publish zap note");
-    tokio::spawn(async move {
-        publish(combined_relays, publish_message).await;
-    });
+    Ok((combined_relays, publish_message))
 }
 
-pub async fn publish(relays: Vec<String>, publish_message: String) {
+/// Sends `publish_message` to every relay. Returns how many accepted it.
+pub async fn publish(relays: Vec<String>, publish_message: String) -> usize {
     info!(target: "server::publish", "Starting publish to {} relays", relays.len());
     let mut futures = vec![];
 
@@ -168,6 +171,36 @@ pub async fn publish(relays: Vec<String>, publish_message: String) {
         success_count,
         failure_count
     );
+
+    success_count
+}
+
+/// Reads until the relay answers the event with a NIP-20 `OK`. Returns whether it was accepted.
+async fn wait_for_ok(uri: &str, socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> bool {
+    while let Some(incoming) = socket.next().await {
+        let text = match incoming {
+            Ok(SocketMessage::Text(text)) => text,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(target: "server::publish", "Failed to read from {}: {}", uri, e);
+                return false;
+            }
+        };
+
+        let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+
+        if parts.first().and_then(Value::as_str) == Some("OK") {
+            let accepted = parts.get(2) == Some(&Value::Bool(true));
+            if !accepted {
+                warn!(target: "server::publish", "Relay {} rejected event: {:?}", uri, parts.get(3));
+            }
+            return accepted;
+        }
+    }
+
+    false
 }
 
 async fn send_message(uri: String, message: String) -> Result<(), ()> {
@@ -185,7 +218,7 @@ async fn send_message(uri: String, message: String) -> Result<(), ()> {
 
     match socket.send(SocketMessage::Text(message)).await {
         Ok(_) => {
-            info!(target: "server::publish", "Successfully sent message to {}", uri);
+            debug!(target: "server::publish", "Successfully sent message to {}", uri);
         }
         Err(e) => {
             warn!(target: "server::publish", "Failed to send message to {}: {}", uri, e);
@@ -193,14 +226,112 @@ async fn send_message(uri: String, message: String) -> Result<(), ()> {
         }
     }
 
-    match socket.close(None).await {
-        Ok(_) => {
-            debug!(target: "server::publish", "Successfully closed connection to {}", uri);
-            Ok(())
+    let accepted = match timeout(ACCEPT_TIMEOUT, wait_for_ok(&uri, &mut socket)).await {
+        Ok(accepted) => accepted,
+        Err(_) => {
+            warn!(target: "server::publish", "No answer from {} within {}s", uri, ACCEPT_TIMEOUT.as_secs());
+            false
         }
+    };
+
+    if let Err(e) = socket.close(None).await {
+        debug!(target: "server::publish", "Failed to close socket connection for {}: {}", uri, e);
+    }
+
+    if accepted {
+        info!(target: "server::publish", "Relay {} accepted the event", uri);
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Opens and closes a connection to `relay`, to tell whether it is reachable.
+pub async fn check_relay(relay: &str) -> Result<(), String> {
+    debug!(target: "server::publish", "Checking relay: {}", relay);
+    let (mut socket, _) = match connect_async(relay).await {
+        Ok(connection) => connection,
         Err(e) => {
-            warn!(target: "server::publish", "Failed to close socket connection for {}: {}", uri, e);
-            Err(())
+            warn!(target: "server::publish", "Failed to connect to {}: {}", relay, e);
+            return Err("FailedToConnectToRelay".to_string());
+        }
+    };
+
+    if let Err(e) = socket.close(None).await {
+        debug!(target: "server::publish", "Failed to close socket connection for {}: {}", relay, e);
+    }
+    Ok(())
+}
+
+/// Subscribes to `filter` on `relay`, sends `message`, and returns the first event the relay
+/// delivers for that subscription.
+pub async fn send_and_wait_for_event(
+    relay: &str,
+    filter: Value,
+    message: String,
+) -> Result<SignedEvent, String> {
+    debug!(target: "server::publish", "Attempting to connect to relay: {}", relay);
+    let (mut socket, _) = match connect_async(relay).await {
+        Ok(connection) => connection,
+        Err(err) => {
+            warn!(target: "server::publish", "Failed to connect to {}: {}", relay, err);
+            return Err("FailedToConnectToRelay".to_string());
+        }
+    };
+
+    let subscription_id = hex::encode(rand::random::<[u8; 8]>());
+    let subscription = json!(["REQ", subscription_id, filter]).to_string();
+
+    for outgoing in [subscription, message] {
+        if let Err(e) = socket.send(SocketMessage::Text(outgoing)).await {
+            warn!(target: "server::publish", "Failed to send message to {}: {}", relay, e);
+            return Err("FailedToSendMessageToRelay".to_string());
+        }
+    }
+
+    let mut result = Err("RelayClosedConnection".to_string());
+
+    while let Some(incoming) = socket.next().await {
+        let text = match incoming {
+            Ok(SocketMessage::Text(text)) => text,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(target: "server::publish", "Failed to read from {}: {}", relay, e);
+                result = Err("FailedToReadFromRelay".to_string());
+                break;
+            }
+        };
+
+        let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+
+        match parts.first().and_then(Value::as_str) {
+            Some("EVENT") if parts.get(1) == Some(&json!(subscription_id)) => {
+                result = parts
+                    .get(2)
+                    .cloned()
+                    .and_then(|event| serde_json::from_value::<SignedEvent>(event).ok())
+                    .ok_or_else(|| "FailedToParseRelayEvent".to_string());
+                break;
+            }
+            Some("OK") if parts.get(2) == Some(&Value::Bool(false)) => {
+                warn!(target: "server::publish", "Relay {} rejected event: {:?}", relay, parts.get(3));
+                result = Err("RelayRejectedEvent".to_string());
+                break;
+            }
+            Some("CLOSED") if parts.get(1) == Some(&json!(subscription_id)) => {
+                warn!(target: "server::publish", "Relay {} closed subscription: {:?}", relay, parts.get(2));
+                result = Err("RelayClosedSubscription".to_string());
+                break;
+            }
+            _ => {}
         }
     }
+
+    if let Err(e) = socket.close(None).await {
+        debug!(target: "server::publish", "Failed to close socket connection for {}: {}", relay, e);
+    }
+
+    result
 }
@@ -0,0 +1,127 @@
+use anyhow::anyhow;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use reqwest::Url;
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+use urlencoding::encode;
+
+use crate::{config::SuccessAction, server::encryption::aes256_cbc_encrypt};
+
+/// Longest `message` and `description` wallets are required to show.
+const MAX_TEXT_LENGTH: usize = 144;
+
+const DEFAULT_MESSAGE: &str = "Payment received!";
+
+/// What success action templates can refer to.
+pub struct SuccessActionContext<'a> {
+    pub username: &'a str,
+    pub amount_msat: i64,
+    pub payment_hash: &'a [u8],
+    pub comment: &'a str,
+}
+
+fn render_with(
+    template: &str,
+    context: &SuccessActionContext,
+    escape: impl Fn(&str) -> String,
+) -> String {
+    template
+        .replace("{username}", &escape(context.username))
+        .replace("{amount_msat}", &context.amount_msat.to_string())
+        .replace("{amount_sat}", &(context.amount_msat / 1000).to_string())
+        .replace("{payment_hash}", &hex::encode(context.payment_hash))
+        .replace("{comment}", &escape(context.comment))
+}
+
+fn render(template: &str, context: &SuccessActionContext) -> String {
+    render_with(template, context, str::to_string)
+}
+
+/// Like `render`, with the payer's values percent-encoded so they cannot add path segments or
+/// query parameters to the url.
+fn render_url(template: &str, context: &SuccessActionContext) -> String {
+    render_with(template, context, |value| encode(value).into_owned())
+}
+
+fn render_short(template: &str, context: &SuccessActionContext) -> String {
+    let text = render(template, context);
+
+    if text.chars().count() > MAX_TEXT_LENGTH {
+        warn!(target: "server::success_action", "Success action text longer than {} characters, truncating", MAX_TEXT_LENGTH);
+        text.chars().take(MAX_TEXT_LENGTH).collect()
+    } else {
+        text
+    }
+}
+
+/// Whether the invoice's preimage has to be chosen by rustdress, to encrypt with it.
+pub fn needs_preimage(action: Option<&SuccessAction>) -> bool {
+    matches!(action, Some(SuccessAction::Aes { .. }))
+}
+
+/// Checks that a `url` action points at `domain`, the callback's domain, as LUD-09 requires.
+pub fn check_success_action(
+    action: Option<&SuccessAction>,
+    domain: &str,
+) -> Result<(), anyhow::Error> {
+    let Some(SuccessAction::Url { url, .. }) = action else {
+        return Ok(());
+    };
+
+    let host = Url::parse(url)
+        .map_err(|e| anyhow!("InvalidSuccessActionUrl {}: {}", url, e))?
+        .host_str()
+        .map(str::to_lowercase);
+    let domain = Url::parse(&format!("https://{}", domain))?
+        .host_str()
+        .map(str::to_lowercase);
+
+    if host.is_none() || host != domain {
+        return Err(anyhow!(
+            "SuccessActionUrlNotOnDomain: {} is not on {}",
+            url,
+            domain.unwrap_or_default()
+        ));
+    }
+
+    Ok(())
+}
+
+/// The `successAction` of a callback response, or the default message when none is set.
+pub fn get_success_action(
+    action: Option<&SuccessAction>,
+    context: &SuccessActionContext,
+    preimage: Option<&[u8; 32]>,
+) -> Result<Value, String> {
+    let success_action = match action {
+        None => json!({ "tag": "message", "message": DEFAULT_MESSAGE }),
+        Some(SuccessAction::Message { message }) => {
+            json!({ "tag": "message", "message": render_short(message, context) })
+        }
+        Some(SuccessAction::Url { description, url }) => json!({
+            "tag": "url",
+            "description": render_short(description, context),
+            "url": render_url(url, context),
+        }),
+        Some(SuccessAction::Aes {
+            description,
+            plaintext,
+        }) => {
+            let preimage =
+                preimage.ok_or_else(|| "MissingPreimageForAesSuccessAction".to_string())?;
+            let iv: [u8; 16] = rand::random();
+            let ciphertext =
+                aes256_cbc_encrypt(preimage, &iv, render(plaintext, context).as_bytes());
+
+            json!({
+                "tag": "aes",
+                "description": render_short(description, context),
+                "ciphertext": STANDARD.encode(ciphertext),
+                "iv": STANDARD.encode(iv),
+            })
+        }
+    };
+    debug!(target: "server::success_action", "Success action: {}", success_action["tag"]);
+
+    Ok(success_action)
+}
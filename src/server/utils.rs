@@ -1,27 +1,46 @@
 use std::{
-    collections::HashSet,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::anyhow;
 use bech32::{ToBase32, Variant, encode};
-use lnd_grpc_rust::{
-    LndClient,
-    invoicesrpc::SubscribeSingleInvoiceRequest,
-    lnrpc::{Invoice, invoice::InvoiceState},
-};
+use once_cell::sync::Lazy;
 use rusted_nostr_tools::{
     GeneratePublicKey,
     event_methods::{SignedEvent, UnsignedEvent, get_event_hash, sign_event},
 };
-use tracing::{debug, error, info};
+use tokio::time::timeout;
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    config::get_config,
-    credentials::get_lnd::get_lnd,
-    server::{constants::CONSTANTS, parsing_functions::convert_key, publish_to_relay::publish},
+    config::{BlindedPaths, PayerData, SuccessAction, get_config},
+    credentials::lightning_backend::{
+        CreatedInvoice, InvoiceError, InvoiceRequest, LightningBackend, get_named_backend,
+        get_user_backend, get_user_backend_name,
+    },
+    server::{
+        constants::CONSTANTS,
+        parsing_functions::convert_key,
+        publish_to_relay::publish,
+        success_action::{check_success_action, needs_preimage},
+        zap_receipts::{PayerRequest, track_invoice, track_zap},
+    },
 };
 
-use super::{constants::Nip05EventDetails, publish_to_relay::publish_zap_to_relays};
+use super::constants::Nip05EventDetails;
+
+const INVOICE_TIMEOUT: Duration = Duration::from_secs(30);
+const INBOUND_CAPACITY_TIMEOUT: Duration = Duration::from_secs(10);
+const INBOUND_CAPACITY_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Last inbound capacity seen per `[[backends]]` name, `None` being the default backend.
+static INBOUND_CAPACITY: Lazy<Mutex<HashMap<Option<String>, CapacityCheck>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// When inbound capacity was checked, and what the node reported.
+type CapacityCheck = (Instant, Option<i64>);
 
 pub fn get_identifiers(name: Option<&str>) -> (String, String) {
     debug!(target: "server::utils", "Loading identifiers from config for name: {:?}", name);
@@ -40,6 +59,142 @@ pub fn get_identifiers(name: Option<&str>) -> (String, String) {
     (config.domain.clone(), username)
 }
 
+/// Invoice limits for one user: their own settings, else the global ones, else the defaults.
+#[derive(Debug, Clone)]
+pub struct InvoicePolicy {
+    pub min_sendable_msat: i64,
+    pub max_sendable_msat: i64,
+    pub comment_allowed: usize,
+    pub expiry_secs: i64,
+    /// Set only when blinded paths are enabled.
+    pub blinded_paths: Option<BlindedPaths>,
+    pub payer_data: Option<PayerData>,
+    pub success_action: Option<SuccessAction>,
+}
+
+/// The policy for `name`, resolved to a user the same way as `get_identifiers`.
+pub fn get_invoice_policy(name: Option<&str>) -> InvoicePolicy {
+    let config = get_config();
+    let (_, username) = get_identifiers(name);
+    let user = config.users.iter().find(|u| u.username == username);
+
+    InvoicePolicy {
+        min_sendable_msat: user
+            .and_then(|u| u.min_sendable_msat)
+            .or(config.min_sendable_msat)
+            .unwrap_or(CONSTANTS.min_sendamount),
+        max_sendable_msat: user
+            .and_then(|u| u.max_sendable_msat)
+            .or(config.max_sendable_msat)
+            .unwrap_or(CONSTANTS.max_sendamount),
+        comment_allowed: user
+            .and_then(|u| u.comment_allowed)
+            .or(config.comment_allowed)
+            .unwrap_or(CONSTANTS.max_comment_length),
+        expiry_secs: user
+            .and_then(|u| u.invoice_expiry_secs)
+            .or(config.invoice_expiry_secs)
+            .unwrap_or(CONSTANTS.invoice_expiry_secs),
+        blinded_paths: user
+            .and_then(|u| u.blinded_paths.as_ref())
+            .or(config.blinded_paths.as_ref())
+            .filter(|paths| paths.enabled)
+            .cloned(),
+        payer_data: user.and_then(|u| u.payer_data.clone()),
+        success_action: user
+            .and_then(|u| u.success_action.as_ref())
+            .or(config.success_action.as_ref())
+            .cloned(),
+    }
+}
+
+/// Checks every user's policy for settings that parse but cannot work, so they stop rustdress
+/// at startup.
+pub fn check_invoice_policies() -> Result<(), anyhow::Error> {
+    for user in &get_config().users {
+        let (domain, _) = get_identifiers(Some(&user.username));
+        let policy = get_invoice_policy(Some(&user.username));
+
+        if policy.min_sendable_msat > policy.max_sendable_msat {
+            return Err(anyhow!(
+                "MinSendableAboveMaxSendable for {}: {} > {}",
+                user.username,
+                policy.min_sendable_msat,
+                policy.max_sendable_msat
+            ));
+        }
+
+        check_success_action(policy.success_action.as_ref(), &domain)
+            .map_err(|e| anyhow!("{} for {}", e, user.username))?;
+
+        if needs_preimage(policy.success_action.as_ref())
+            && !get_user_backend(&user.username).supports_chosen_preimage()
+        {
+            return Err(anyhow!(
+                "AesSuccessActionNotSupported for {}: its backend cannot create invoices with a given preimage",
+                user.username
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The user's node's inbound capacity, re-checked at most every `INBOUND_CAPACITY_CACHE_TTL`.
+async fn get_inbound_capacity(username: &str) -> Option<i64> {
+    let backend_name = get_user_backend_name(username);
+
+    if let Some((checked_at, capacity)) = INBOUND_CAPACITY
+        .lock()
+        .expect("inbound capacity lock poisoned")
+        .get(&backend_name)
+        && checked_at.elapsed() < INBOUND_CAPACITY_CACHE_TTL
+    {
+        return *capacity;
+    }
+
+    let backend = get_named_backend(backend_name.as_deref());
+    let capacity = match timeout(INBOUND_CAPACITY_TIMEOUT, backend.get_inbound_capacity()).await {
+        Ok(Ok(capacity)) => capacity,
+        Ok(Err(e)) => {
+            warn!(target: "server::utils", "Failed to check inbound capacity: {}", e);
+            None
+        }
+        Err(_) => {
+            warn!(target: "server::utils", "Timed out checking inbound capacity");
+            None
+        }
+    };
+
+    INBOUND_CAPACITY
+        .lock()
+        .expect("inbound capacity lock poisoned")
+        .insert(backend_name, (Instant::now(), capacity));
+
+    capacity
+}
+
+/// `get_invoice_policy` with `max_sendable_msat` lowered to what the node can receive less the
+/// safety margin, though never below `min_sendable_msat`.
+pub async fn get_live_invoice_policy(name: Option<&str>) -> InvoicePolicy {
+    let mut policy = get_invoice_policy(name);
+    let (_, username) = get_identifiers(name);
+
+    if let Some(capacity) = get_inbound_capacity(&username).await {
+        let margin = get_config()
+            .inbound_safety_margin_msat
+            .unwrap_or(CONSTANTS.inbound_safety_margin_msat);
+        let receivable = capacity - margin;
+
+        if receivable < policy.max_sendable_msat {
+            debug!(target: "server::utils", "Limiting maxSendable for {} to {} by inbound capacity", username, receivable);
+            policy.max_sendable_msat = receivable.max(policy.min_sendable_msat);
+        }
+    }
+
+    policy
+}
+
 pub fn bech32_encode(prefix: String, data: String) -> Result<String, bech32::Error> {
     debug!(target: "server::utils", "Encoding data to bech32. Prefix: {}", prefix);
     let base32_data = data.to_base32();
@@ -76,94 +231,116 @@ pub fn get_nostr_keys() -> Result<(String, String), String> {
     Ok((converted_priv_key, pubkey_hex.to_string()))
 }
 
+/// Builds and signs an event with `privkey`, stamped with the current time.
+pub fn sign_nostr_event(
+    privkey: &str,
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: String,
+) -> Result<SignedEvent, String> {
+    let binding = GeneratePublicKey::new(privkey);
+    let pubkey = binding.hex_public_key().to_string();
+
+    let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    let event = UnsignedEvent {
+        content,
+        created_at: current_time.as_secs() as i64,
+        kind,
+        tags,
+        pubkey,
+    };
+
+    sign_event(&event, privkey).map_err(|e| {
+        error!(target: "server::utils", "Failed to sign event: {}", e);
+        "FailedToSignEvent".to_string()
+    })
+}
+
 pub async fn create_invoice(
+    username: &str,
     digest: Vec<u8>,
+    description: String,
     comment: String,
     amount: i64,
     nostr_query: Result<SignedEvent, String>,
-) -> String {
+    payer_request: PayerRequest,
+) -> Result<(CreatedInvoice, Option<[u8; 32]>), InvoiceError> {
     info!(target: "server::utils", "Creating invoice for amount: {}, comment: {}", amount, comment);
-    let mut lnd = get_lnd().await;
-
-    let result = match lnd
-        .lightning()
-        .add_invoice(Invoice {
-            description_hash: digest,
-            expiry: 300,
-            memo: comment.clone(),
-            private: add_hop_hints(),
-            value_msat: amount,
-            ..Default::default()
-        })
-        .await
-    {
-        Ok(result) => result,
-        Err(e) => {
-            error!(target: "server::utils", "Failed to create invoice: {}", e);
-            panic!("FailedToCreateInvoice");
-        }
+    let backend = get_user_backend(username);
+    let policy = get_invoice_policy(Some(username));
+    let expiry = policy.expiry_secs;
+
+    // Route hints are only left out where the backend really adds blinded paths instead, and
+    // the payer did not ask for them.
+    let blinded_paths = policy
+        .blinded_paths
+        .filter(|_| backend.supports_blinded_paths() && !payer_request.route_hints_only);
+
+    let request = InvoiceRequest {
+        description,
+        description_hash: digest,
+        expiry,
+        memo: comment.clone(),
+        private: add_hop_hints() && blinded_paths.is_none(),
+        amount_msat: amount,
+        blinded_paths,
+        preimage: needs_preimage(policy.success_action.as_ref()).then(rand::random),
     };
+    let preimage = request.preimage;
+
+    let mut result = request_invoice(&backend, request.clone()).await;
+
+    // Nodes without enough public channels for a blinded path reject the invoice, so it is
+    // issued the usual way instead. Payers whose wallets cannot pay blinded paths ask for route
+    // hints with `blinded=0`.
+    if request.blinded_paths.is_some() && matches!(result, Err(InvoiceError::Rejected(_))) {
+        info!(target: "server::utils", "Blinded paths unavailable, falling back to route hints");
+        result = request_invoice(
+            &backend,
+            InvoiceRequest {
+                private: add_hop_hints(),
+                blinded_paths: None,
+                ..request
+            },
+        )
+        .await;
+    }
+    let invoice_result = result?;
 
-    let invoice_result = result.into_inner();
     info!(target: "server::utils", "Created invoice with payment request: {}", invoice_result.payment_request);
-
-    if nostr_query.is_ok() {
-        let r_hash = invoice_result.r_hash;
-        let zap_request = nostr_query.unwrap();
-        let comment_clone = comment.clone();
-        debug!(target: "server::utils", "Starting invoice watcher for zap request");
-        tokio::spawn(async move {
-            watch_invoice(zap_request, lnd, &r_hash, &comment_clone).await;
-        });
+    track_invoice(
+        username,
+        &invoice_result.payment_hash,
+        expiry,
+        payer_request,
+    );
+
+    if let Ok(zap_request) = nostr_query {
+        track_zap(
+            username,
+            &invoice_result.payment_hash,
+            zap_request,
+            &comment,
+            expiry,
+        );
     }
-    invoice_result.payment_request
+    Ok((invoice_result, preimage))
 }
 
-async fn watch_invoice(zap_request: SignedEvent, mut lnd: LndClient, r_hash: &[u8], comment: &str) {
-    debug!(target: "server::utils", "Starting to watch invoice for payment");
-    let mut invoice_subscription = match lnd
-        .invoices()
-        .subscribe_single_invoice(SubscribeSingleInvoiceRequest {
-            r_hash: r_hash.to_vec(),
-        })
-        .await
-    {
-        Ok(sub) => {
-            debug!(target: "server::utils", "Successfully subscribed to invoice updates");
-            sub.into_inner()
-        }
-        Err(e) => {
-            error!(target: "server::utils", "Failed to subscribe to invoice: {}", e);
-            return;
-        }
-    };
-
-    while let Some(invoice) = match invoice_subscription.message().await {
-        Ok(msg) => msg,
-        Err(e) => {
-            error!(target: "server::utils", "Failed to receive invoice subscription info: {}", e);
-            return;
+async fn request_invoice(
+    backend: &Arc<dyn LightningBackend>,
+    request: InvoiceRequest,
+) -> Result<CreatedInvoice, InvoiceError> {
+    match timeout(INVOICE_TIMEOUT, backend.create_invoice(request)).await {
+        Ok(Ok(invoice)) => Ok(invoice),
+        Ok(Err(e)) => {
+            warn!(target: "server::utils", "Failed to create invoice: {:#}", e);
+            Err(InvoiceError::from_backend(e))
         }
-    } {
-        if let Ok(state) = InvoiceState::try_from(invoice.state) {
-            debug!(target: "server::utils", "Invoice state update: {:?}", state);
-            // If this invoice was Settled we can do something with it
-            if state == InvoiceState::Settled {
-                info!(target: "server::utils", "Invoice settled, publishing zap to relays");
-                publish_zap_to_relays(
-                    zap_request,
-                    comment,
-                    invoice.payment_request,
-                    invoice.r_preimage,
-                    invoice.settle_date,
-                );
-                break;
-            }
-
-            if state == InvoiceState::Settled {
-                debug!(target: "server::utils", "Invoice settled, stopping watch");
-                break;
-            }
+        Err(_) => {
+            warn!(target: "server::utils", "Timed out creating invoice");
+            Err(InvoiceError::NodeOffline)
         }
     }
 }
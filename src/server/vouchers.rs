@@ -0,0 +1,426 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::time::timeout;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::get_voucher_path,
+    credentials::lightning_backend::{PaymentFailed, get_backend},
+    server::utils::{bech32_encode, get_identifiers},
+};
+
+const MAX_VOUCHERS_PER_BATCH: u32 = 1000;
+
+const DEFAULT_DESCRIPTION: &str = "rustdress voucher";
+
+/// Routing fees a payout may spend, in parts per million of the voucher amount, with a floor
+/// so that small vouchers can still be routed.
+const MAX_FEE_PPM: i64 = 10_000;
+const MIN_MAX_FEE_MSAT: i64 = 10_000;
+
+/// After this long a payout's outcome is treated as unknown.
+const PAYOUT_TIMEOUT: Duration = Duration::from_secs(120);
+
+static VOUCHERS: Lazy<Mutex<VoucherStore>> = Lazy::new(|| Mutex::new(VoucherStore::load()));
+
+/// What an admin asks for when creating vouchers.
+#[derive(Deserialize)]
+pub struct NewBatch {
+    pub amount_msat: i64,
+    /// How many vouchers to create, each good for one withdrawal.
+    pub uses: u32,
+    pub expiry_secs: i64,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "lowercase")]
+enum VoucherState {
+    Unused,
+    /// A payout started and has not finished, or its outcome is unknown. Such vouchers are
+    /// never paid out again.
+    Paying {
+        since: i64,
+    },
+    Redeemed {
+        redeemed_at: i64,
+        payment_hash: String,
+        /// Proof that the wallet's invoice was paid.
+        preimage: String,
+        fee_msat: i64,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Voucher {
+    /// Unguessable, as anyone with the voucher's URL can withdraw it.
+    id: String,
+    k1: String,
+    #[serde(flatten)]
+    state: VoucherState,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VoucherBatch {
+    id: String,
+    amount_msat: i64,
+    description: String,
+    created_at: i64,
+    expires_at: i64,
+    vouchers: Vec<Voucher>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct VoucherStore {
+    #[serde(default)]
+    batches: Vec<VoucherBatch>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn voucher_url(id: &str) -> String {
+    let (domain, _) = get_identifiers(None);
+    format!("https://{}/lnurlw/{}", domain, id)
+}
+
+impl VoucherStore {
+    fn load() -> Self {
+        let path = get_voucher_path();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!(target: "server::vouchers", "No vouchers at {}, starting fresh", path.display());
+                return VoucherStore::default();
+            }
+            Err(e) => {
+                error!(target: "server::vouchers", "Failed to read {}: {}", path.display(), e);
+                panic!("Failed to read voucher file");
+            }
+        };
+
+        match serde_json::from_str::<VoucherStore>(&contents) {
+            Ok(store) => {
+                info!(target: "server::vouchers", "Loaded {} voucher batches from {}", store.batches.len(), path.display());
+                store
+            }
+            Err(e) => {
+                error!(target: "server::vouchers", "Failed to parse {}: {}", path.display(), e);
+                panic!("Failed to parse voucher file");
+            }
+        }
+    }
+
+    /// Writes the vouchers through a temporary file, so a crash never leaves them half written.
+    fn save(&self) -> Result<(), String> {
+        let path = get_voucher_path();
+        let temp_path = path.with_extension("json.tmp");
+
+        serde_json::to_vec_pretty(self)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(fs::write(&temp_path, contents)?))
+            .and_then(|()| Ok(fs::rename(&temp_path, &path)?))
+            .map_err(|e| {
+                error!(target: "server::vouchers", "Failed to save {}: {}", path.display(), e);
+                "FailedToSaveVouchers".to_string()
+            })
+    }
+
+    /// Indexes of the batch holding the voucher with `id`, and of the voucher in it.
+    fn position(&self, id: &str) -> Option<(usize, usize)> {
+        self.batches.iter().enumerate().find_map(|(b, batch)| {
+            batch
+                .vouchers
+                .iter()
+                .position(|voucher| voucher.id == id)
+                .map(|v| (b, v))
+        })
+    }
+}
+
+impl VoucherBatch {
+    /// The batch as shown to admins, with each voucher's LNURL and without its k1.
+    fn view(&self) -> Value {
+        let vouchers: Vec<Value> = self
+            .vouchers
+            .iter()
+            .map(|voucher| {
+                let url = voucher_url(&voucher.id);
+                let mut view = serde_json::to_value(&voucher.state).unwrap_or_else(|_| json!({}));
+                view["id"] = json!(voucher.id);
+                view["lnurl"] = json!(bech32_encode("lnurl".to_string(), url.clone()).ok());
+                view["url"] = json!(url);
+                view
+            })
+            .collect();
+
+        json!({
+            "id": self.id,
+            "amount_msat": self.amount_msat,
+            "description": self.description,
+            "created_at": self.created_at,
+            "expires_at": self.expires_at,
+            "vouchers": vouchers,
+        })
+    }
+
+    fn check_redeemable(&self, voucher: &Voucher) -> Result<(), String> {
+        if self.expires_at <= now() {
+            return Err("VoucherExpired".to_string());
+        }
+
+        if voucher.state != VoucherState::Unused {
+            return Err("VoucherAlreadyUsed".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// The amount of a BOLT11 invoice, read from its human-readable part.
+fn invoice_amount_msat(payment_request: &str) -> Result<i64, String> {
+    let payment_request = payment_request.to_lowercase();
+    let hrp = payment_request
+        .rsplit_once('1')
+        .map(|(hrp, _)| hrp)
+        .filter(|hrp| hrp.starts_with("ln"))
+        .ok_or_else(|| "InvalidPaymentRequest".to_string())?;
+
+    // What follows `ln` is the currency prefix, then the amount and its multiplier.
+    let amount = hrp[2..].trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    if amount.is_empty() {
+        return Err("InvoiceWithoutAmount".to_string());
+    }
+
+    let (digits, multiplier) = match amount.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&amount[..i], Some(c)),
+        _ => (amount, None),
+    };
+    let value: i64 = digits
+        .parse()
+        .map_err(|_| "InvalidInvoiceAmount".to_string())?;
+
+    // One bitcoin is 10^11 millisatoshis.
+    let amount_msat = match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') if value % 10 == 0 => Some(value / 10),
+        _ => None,
+    };
+
+    amount_msat
+        .filter(|amount_msat| *amount_msat > 0)
+        .ok_or_else(|| "InvalidInvoiceAmount".to_string())
+}
+
+/// Creates `uses` vouchers for `amount_msat` each, and returns the batch as shown to admins.
+pub fn create_batch(request: NewBatch) -> Result<Value, String> {
+    if request.amount_msat < 1000 {
+        return Err("VoucherAmountBelowOneSatoshi".to_string());
+    }
+
+    if request.uses == 0 || request.uses > MAX_VOUCHERS_PER_BATCH {
+        return Err(format!(
+            "VoucherUsesMustBeBetween1And{}",
+            MAX_VOUCHERS_PER_BATCH
+        ));
+    }
+
+    if request.expiry_secs <= 0 {
+        return Err("VoucherExpiryMustBePositive".to_string());
+    }
+
+    let created_at = now();
+    let batch = VoucherBatch {
+        id: hex::encode(rand::random::<[u8; 8]>()),
+        amount_msat: request.amount_msat,
+        description: request
+            .description
+            .unwrap_or_else(|| DEFAULT_DESCRIPTION.to_string()),
+        created_at,
+        expires_at: created_at.saturating_add(request.expiry_secs),
+        vouchers: (0..request.uses)
+            .map(|_| Voucher {
+                id: hex::encode(rand::random::<[u8; 16]>()),
+                k1: hex::encode(rand::random::<[u8; 32]>()),
+                state: VoucherState::Unused,
+            })
+            .collect(),
+    };
+    let view = batch.view();
+
+    let mut store = VOUCHERS.lock().expect("voucher lock poisoned");
+    store.batches.push(batch);
+    if let Err(e) = store.save() {
+        store.batches.pop();
+        return Err(e);
+    }
+    info!(target: "server::vouchers", "Created {} vouchers of {} msat in batch {}", request.uses, request.amount_msat, view["id"]);
+
+    Ok(view)
+}
+
+/// Every batch as shown to admins, newest first.
+pub fn list_batches() -> Value {
+    let store = VOUCHERS.lock().expect("voucher lock poisoned");
+    let batches: Vec<Value> = store.batches.iter().rev().map(VoucherBatch::view).collect();
+
+    json!(batches)
+}
+
+/// The LUD-03 `withdrawRequest` for the voucher with `id`.
+pub fn get_withdraw_request(id: &str) -> Result<Value, String> {
+    let store = VOUCHERS.lock().expect("voucher lock poisoned");
+    let (b, v) = store
+        .position(id)
+        .ok_or_else(|| "VoucherNotFound".to_string())?;
+    let batch = &store.batches[b];
+    let voucher = &batch.vouchers[v];
+    batch.check_redeemable(voucher)?;
+
+    Ok(json!({
+        "tag": "withdrawRequest",
+        "callback": format!("{}/callback", voucher_url(id)),
+        "k1": voucher.k1,
+        "defaultDescription": batch.description,
+        "minWithdrawable": batch.amount_msat,
+        "maxWithdrawable": batch.amount_msat,
+    }))
+}
+
+/// Claims the voucher with `id` and starts paying `payment_request` from the default backend.
+/// The claim is saved before paying, so a voucher is paid out at most once.
+pub fn start_withdrawal(id: &str, k1: &str, payment_request: &str) -> Result<(), String> {
+    let amount_msat = invoice_amount_msat(payment_request)?;
+
+    {
+        let mut store = VOUCHERS.lock().expect("voucher lock poisoned");
+        let (b, v) = store
+            .position(id)
+            .ok_or_else(|| "VoucherNotFound".to_string())?;
+        let batch = &store.batches[b];
+        let voucher = &batch.vouchers[v];
+
+        if voucher.k1 != k1 {
+            return Err("InvalidK1".to_string());
+        }
+        batch.check_redeemable(voucher)?;
+
+        if amount_msat != batch.amount_msat {
+            return Err("InvoiceAmountDoesNotMatchVoucher".to_string());
+        }
+
+        store.batches[b].vouchers[v].state = VoucherState::Paying { since: now() };
+        if let Err(e) = store.save() {
+            store.batches[b].vouchers[v].state = VoucherState::Unused;
+            return Err(e);
+        }
+    }
+    debug!(target: "server::vouchers", "Claimed voucher {}", id);
+
+    tokio::spawn(pay_out(
+        id.to_string(),
+        payment_request.to_string(),
+        amount_msat,
+    ));
+
+    Ok(())
+}
+
+async fn pay_out(id: String, payment_request: String, amount_msat: i64) {
+    let max_fee_msat = (amount_msat * MAX_FEE_PPM / 1_000_000).max(MIN_MAX_FEE_MSAT);
+    let backend = get_backend();
+    let result = match timeout(
+        PAYOUT_TIMEOUT,
+        backend.pay_invoice(&payment_request, max_fee_msat),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("VoucherPayoutTimedOut")),
+    };
+
+    let state = match result {
+        Ok(paid) => {
+            info!(target: "server::vouchers", "Paid out voucher {} with {} msat in fees", id, paid.fee_msat);
+            VoucherState::Redeemed {
+                redeemed_at: now(),
+                payment_hash: hex::encode(paid.payment_hash),
+                preimage: hex::encode(paid.preimage),
+                fee_msat: paid.fee_msat,
+            }
+        }
+        Err(e) if PaymentFailed::is_cause_of(&e) => {
+            warn!(target: "server::vouchers", "Payout of voucher {} failed, it can be withdrawn again: {}", id, e);
+            VoucherState::Unused
+        }
+        Err(e) => {
+            error!(target: "server::vouchers", "Payout of voucher {} may still complete, leaving it claimed. Check the node's payments: {}", id, e);
+            return;
+        }
+    };
+
+    let mut store = VOUCHERS.lock().expect("voucher lock poisoned");
+    if let Some((b, v)) = store.position(&id) {
+        store.batches[b].vouchers[v].state = state;
+        // A failed save leaves the voucher claimed on disk, which refuses it rather than paying
+        // it twice.
+        let _ = store.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_amount_with_each_multiplier() {
+        assert_eq!(invoice_amount_msat("lnbc20m1pvjluez"), Ok(2_000_000_000));
+        assert_eq!(invoice_amount_msat("lnbc2500u1pvjluez"), Ok(250_000_000));
+        assert_eq!(invoice_amount_msat("lnbc10n1pvjluez"), Ok(1000));
+        assert_eq!(invoice_amount_msat("lnbc10p1pvjluez"), Ok(1));
+    }
+
+    #[test]
+    fn reads_amount_without_multiplier_as_bitcoin() {
+        assert_eq!(invoice_amount_msat("lnbc11pvjluez"), Ok(100_000_000_000));
+    }
+
+    #[test]
+    fn reads_amount_after_any_currency_prefix() {
+        assert_eq!(invoice_amount_msat("lnbcrt1500n1pvjluez"), Ok(150_000));
+        assert_eq!(invoice_amount_msat("LNTB2500U1PVJLUEZ"), Ok(250_000_000));
+    }
+
+    #[test]
+    fn refuses_pico_amounts_that_are_not_whole_millisatoshis() {
+        assert_eq!(
+            invoice_amount_msat("lnbc15p1pvjluez"),
+            Err("InvalidInvoiceAmount".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_invoices_without_amount() {
+        assert_eq!(
+            invoice_amount_msat("lnbc1pvjluez"),
+            Err("InvoiceWithoutAmount".to_string())
+        );
+    }
+}
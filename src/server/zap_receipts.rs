@@ -0,0 +1,505 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use once_cell::sync::{Lazy, OnceCell};
+use rusted_nostr_tools::event_methods::SignedEvent;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{sync::mpsc, time::sleep};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::{get_config, get_state_path},
+    credentials::lightning_backend::{SettledInvoice, get_named_backend, get_user_backend_name},
+    server::{
+        price_source::FiatConversion,
+        publish_to_relay::{build_zap_receipt, publish},
+    },
+};
+
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// How long an unpaid zap is remembered after its invoice expires.
+const PENDING_ZAP_RETENTION_SECS: i64 = 24 * 60 * 60;
+
+/// How long an invoice can be verified after it expires.
+const ISSUED_INVOICE_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Caps the memory and state file space the unauthenticated invoice callback can take up.
+const MAX_ISSUED_INVOICES: usize = 10_000;
+
+const STATE_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often receipts no relay accepted yet are published again.
+const RECEIPT_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Loaded by `load_zap_state` at startup.
+static ZAP_STATE: OnceCell<Mutex<ZapState>> = OnceCell::new();
+
+/// Backends without a settlement feed, whose zap invoices are watched one at a time.
+static WATCHED_PER_INVOICE: Lazy<Mutex<HashSet<Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[derive(Serialize, Deserialize)]
+struct PendingZap {
+    /// `[[backends]]` entry the invoice was created on, `None` for the default backend.
+    backend: Option<String>,
+    zap_request: SignedEvent,
+    comment: String,
+    expires_at: i64,
+    /// Set once the invoice settles. The zap is kept until a relay accepts its receipt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    settlement: Option<ZapSettlement>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ZapSettlement {
+    payment_request: String,
+    /// Hex encoded.
+    preimage: String,
+    settle_date: i64,
+}
+
+/// An invoice rustdress created, kept so that only those can be verified.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IssuedInvoice {
+    pub username: String,
+    /// `[[backends]]` entry the invoice was created on, `None` for the default backend.
+    pub backend: Option<String>,
+    pub expires_at: i64,
+    #[serde(flatten)]
+    pub payer_request: PayerRequest,
+}
+
+/// What the payer sent along with the amount.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PayerRequest {
+    /// LUD-18 details the payer gave.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payer_data: Option<Value>,
+    /// The fiat amount the payer asked for, and the price it was converted at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fiat: Option<FiatConversion>,
+    /// Asked with `blinded=0` for route hints instead of blinded paths, for wallets that cannot
+    /// pay those.
+    #[serde(skip)]
+    pub route_hints_only: bool,
+}
+
+/// Zaps still waiting for a receipt, and how far each backend's settlements have been read.
+#[derive(Serialize, Deserialize, Default)]
+struct ZapState {
+    /// Pending zaps by hex payment hash.
+    #[serde(default)]
+    pending: HashMap<String, PendingZap>,
+    /// Last settle index read from the default backend.
+    #[serde(default)]
+    settle_index: u64,
+    /// Last settle index read from each `[[backends]]` entry.
+    #[serde(default)]
+    backend_settle_indexes: HashMap<String, u64>,
+    /// Recently issued invoices by hex payment hash, at most `MAX_ISSUED_INVOICES`.
+    #[serde(default)]
+    issued: HashMap<String, IssuedInvoice>,
+    /// Whether anything changed since the last save.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+impl ZapState {
+    fn load() -> Result<Self, anyhow::Error> {
+        let path = get_state_path();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!(target: "server::zap_receipts", "No state at {}, starting fresh", path.display());
+                return Ok(ZapState::default());
+            }
+            Err(e) => return Err(anyhow!("FailedToReadStateFile {}: {}", path.display(), e)),
+        };
+
+        let state = serde_json::from_str::<ZapState>(&contents)
+            .map_err(|e| anyhow!("FailedToParseStateFile {}: {}", path.display(), e))?;
+        info!(target: "server::zap_receipts", "Loaded {} pending zaps from {}", state.pending.len(), path.display());
+
+        Ok(state)
+    }
+
+    /// Writes the state through a temporary file, so a crash never leaves it half written.
+    fn save(&mut self) {
+        let now = now();
+        // Settled zaps stay until a relay accepts their receipt, however long that takes.
+        self.pending.retain(|_, zap| {
+            zap.settlement.is_some() || zap.expires_at + PENDING_ZAP_RETENTION_SECS > now
+        });
+        self.issued
+            .retain(|_, invoice| invoice.expires_at + ISSUED_INVOICE_RETENTION_SECS > now);
+
+        let path = get_state_path();
+        let temp_path = path.with_extension("json.tmp");
+
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(fs::write(&temp_path, contents)?))
+            .and_then(|()| Ok(fs::rename(&temp_path, &path)?));
+
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(e) => {
+                error!(target: "server::zap_receipts", "Failed to save {}: {}", path.display(), e)
+            }
+        }
+    }
+
+    /// Forgets the invoices closest to expiry once there are more than `MAX_ISSUED_INVOICES`,
+    /// a tenth at a time so the sorting is rare.
+    fn limit_issued(&mut self) {
+        if self.issued.len() <= MAX_ISSUED_INVOICES {
+            return;
+        }
+
+        let mut by_expiry: Vec<(i64, String)> = self
+            .issued
+            .iter()
+            .map(|(payment_hash, invoice)| (invoice.expires_at, payment_hash.clone()))
+            .collect();
+        by_expiry.sort_unstable();
+
+        let excess = self.issued.len() - MAX_ISSUED_INVOICES * 9 / 10;
+        for (_, payment_hash) in by_expiry.into_iter().take(excess) {
+            self.issued.remove(&payment_hash);
+        }
+        warn!(target: "server::zap_receipts", "Over {} issued invoices, forgot the {} oldest", MAX_ISSUED_INVOICES, excess);
+    }
+
+    fn settle_index(&self, backend: Option<&str>) -> u64 {
+        match backend {
+            Some(name) => self
+                .backend_settle_indexes
+                .get(name)
+                .copied()
+                .unwrap_or_default(),
+            None => self.settle_index,
+        }
+    }
+
+    /// Records progress through a backend's settlements. Returns whether it moved forward.
+    fn advance_settle_index(&mut self, backend: Option<&str>, settle_index: u64) -> bool {
+        let current = match backend {
+            Some(name) => self
+                .backend_settle_indexes
+                .entry(name.to_string())
+                .or_default(),
+            None => &mut self.settle_index,
+        };
+
+        if settle_index > *current {
+            *current = settle_index;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Reads the zap state file. Run once at startup, before anything tracks invoices or zaps.
+pub fn load_zap_state() -> Result<(), anyhow::Error> {
+    let state = ZapState::load()?;
+    // A second call keeps the state already loaded.
+    let _ = ZAP_STATE.set(Mutex::new(state));
+
+    Ok(())
+}
+
+fn zap_state() -> MutexGuard<'static, ZapState> {
+    ZAP_STATE
+        .get()
+        .expect("zap state is loaded at startup")
+        .lock()
+        .expect("zap state lock poisoned")
+}
+
+/// Remembers an invoice issued to `username`, so it can be verified later.
+pub fn track_invoice(
+    username: &str,
+    payment_hash: &[u8],
+    expiry: i64,
+    payer_request: PayerRequest,
+) {
+    // Payer data holds personal details, so only its presence is logged.
+    if payer_request.payer_data.is_some() {
+        debug!(target: "server::zap_receipts", "Invoice {} for {} has payer data", hex::encode(payment_hash), username);
+    }
+    if let Some(fiat) = &payer_request.fiat {
+        info!(target: "server::zap_receipts", "Invoice {} for {} is for {} {} (smallest unit) at {} per BTC",
+            hex::encode(payment_hash), username, fiat.amount, fiat.currency, fiat.btc_price);
+    }
+
+    // Saved by `flush_state`, so requests never wait on writing the state file.
+    let mut state = zap_state();
+    state.issued.insert(
+        hex::encode(payment_hash),
+        IssuedInvoice {
+            username: username.to_string(),
+            backend: get_user_backend_name(username),
+            expires_at: now() + expiry,
+            payer_request,
+        },
+    );
+    state.limit_issued();
+    state.dirty = true;
+}
+
+/// Saves the state every `STATE_FLUSH_INTERVAL` when it changed, forever.
+fn flush_state() {
+    tokio::spawn(async {
+        loop {
+            sleep(STATE_FLUSH_INTERVAL).await;
+
+            let mut state = zap_state();
+            if state.dirty {
+                state.save();
+            }
+        }
+    });
+}
+
+/// The invoice with `payment_hash`, if rustdress issued it.
+pub fn get_issued_invoice(payment_hash: &[u8]) -> Option<IssuedInvoice> {
+    zap_state().issued.get(&hex::encode(payment_hash)).cloned()
+}
+
+/// Remembers a zap until its invoice settles, so the receipt is published even if that
+/// happens while rustdress is restarting.
+pub fn track_zap(
+    username: &str,
+    payment_hash: &[u8],
+    zap_request: SignedEvent,
+    comment: &str,
+    expiry: i64,
+) {
+    let backend = get_user_backend_name(username);
+
+    let watch_now = {
+        let mut state = zap_state();
+        state.pending.insert(
+            hex::encode(payment_hash),
+            PendingZap {
+                backend: backend.clone(),
+                zap_request,
+                comment: comment.to_string(),
+                expires_at: now() + expiry,
+                settlement: None,
+            },
+        );
+        // Saved by `flush_state`, so requests never wait on writing the state file.
+        state.dirty = true;
+
+        WATCHED_PER_INVOICE
+            .lock()
+            .expect("watched backends lock poisoned")
+            .contains(&backend)
+    };
+    debug!(target: "server::zap_receipts", "Tracking zap invoice {}", hex::encode(payment_hash));
+
+    if watch_now {
+        watch_invoice(backend, payment_hash.to_vec());
+    }
+}
+
+/// Follows settlements on every backend that receives zaps, starting with the ones settled
+/// while rustdress was not running.
+pub fn start_zap_receipts() {
+    let mut backends: HashSet<Option<String>> = get_config()
+        .users
+        .iter()
+        .map(|u| get_user_backend_name(&u.username))
+        .collect();
+
+    backends.extend(zap_state().pending.values().map(|zap| zap.backend.clone()));
+
+    for backend in backends {
+        tokio::spawn(follow_settlements(backend));
+    }
+
+    flush_state();
+
+    tokio::spawn(async {
+        loop {
+            retry_receipts().await;
+            sleep(RECEIPT_RETRY_INTERVAL).await;
+        }
+    });
+}
+
+/// Publishes the receipts of zaps that settled but no relay accepted yet, e.g. because
+/// rustdress stopped before publishing them.
+async fn retry_receipts() {
+    let payment_hashes: Vec<String> = zap_state()
+        .pending
+        .iter()
+        .filter(|(_, zap)| zap.settlement.is_some())
+        .map(|(payment_hash, _)| payment_hash.clone())
+        .collect();
+
+    if !payment_hashes.is_empty() {
+        info!(target: "server::zap_receipts", "Publishing {} unpublished zap receipts", payment_hashes.len());
+    }
+
+    for payment_hash in payment_hashes {
+        publish_receipt(payment_hash).await;
+    }
+}
+
+async fn follow_settlements(backend: Option<String>) {
+    let node = get_named_backend(backend.as_deref());
+
+    if !node.supports_settlement_feed() {
+        info!(target: "server::zap_receipts", "Backend {} has no settlement feed, watching zap invoices one by one", backend.as_deref().unwrap_or("default"));
+        WATCHED_PER_INVOICE
+            .lock()
+            .expect("watched backends lock poisoned")
+            .insert(backend.clone());
+
+        let payment_hashes: Vec<Vec<u8>> = zap_state()
+            .pending
+            .iter()
+            .filter(|(_, zap)| zap.backend == backend)
+            .filter_map(|(payment_hash, _)| hex::decode(payment_hash).ok())
+            .collect();
+
+        for payment_hash in payment_hashes {
+            watch_invoice(backend.clone(), payment_hash);
+        }
+        return;
+    }
+
+    loop {
+        let settle_index = zap_state().settle_index(backend.as_deref());
+        debug!(target: "server::zap_receipts", "Following settlements after index {}", settle_index);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let handle_settlements = async {
+            while let Some(invoice) = receiver.recv().await {
+                on_settled(backend.as_deref(), invoice);
+            }
+        };
+
+        let (result, ()) = tokio::join!(
+            node.subscribe_settlements(settle_index, sender),
+            handle_settlements
+        );
+
+        match result {
+            Ok(()) => warn!(target: "server::zap_receipts", "Settlement feed ended, resubscribing"),
+            Err(e) => {
+                warn!(target: "server::zap_receipts", "Settlement feed failed, resubscribing: {}", e)
+            }
+        }
+
+        sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+fn watch_invoice(backend: Option<String>, payment_hash: Vec<u8>) {
+    tokio::spawn(async move {
+        debug!(target: "server::zap_receipts", "Starting to watch invoice for payment");
+        match get_named_backend(backend.as_deref())
+            .wait_for_settlement(&payment_hash)
+            .await
+        {
+            Ok(invoice) => on_settled(backend.as_deref(), invoice),
+            Err(e) => error!(target: "server::zap_receipts", "Failed to watch invoice: {}", e),
+        }
+    });
+}
+
+/// Records that a zap invoice settled, then publishes its receipt.
+fn on_settled(backend: Option<&str>, invoice: SettledInvoice) {
+    let payment_hash = hex::encode(&invoice.payment_hash);
+
+    let newly_settled = {
+        let mut state = zap_state();
+        let advanced = state.advance_settle_index(backend, invoice.settle_index);
+
+        let zap = state
+            .pending
+            .get_mut(&payment_hash)
+            .filter(|zap| zap.settlement.is_none());
+        let newly_settled = zap.is_some();
+        if let Some(zap) = zap {
+            zap.settlement = Some(ZapSettlement {
+                payment_request: invoice.payment_request,
+                preimage: hex::encode(&invoice.preimage),
+                settle_date: invoice.settle_date,
+            });
+        }
+
+        // Most settlements are not zaps, so the index alone is left to `flush_state`.
+        // Settlements replayed after a crash find their zaps already settled or gone.
+        if newly_settled {
+            state.save();
+        } else if advanced {
+            state.dirty = true;
+        }
+        newly_settled
+    };
+
+    if newly_settled {
+        info!(target: "server::zap_receipts", "Invoice settled, publishing zap to relays");
+        tokio::spawn(publish_receipt(payment_hash));
+    }
+}
+
+/// Publishes the receipt for a settled zap, and forgets the zap once a relay accepted it.
+/// Receipts that cannot be built are dropped rather than retried.
+async fn publish_receipt(payment_hash: String) {
+    let receipt = {
+        let state = zap_state();
+        let Some(zap) = state.pending.get(&payment_hash) else {
+            return;
+        };
+        let Some(settlement) = &zap.settlement else {
+            return;
+        };
+
+        let preimage = hex::decode(&settlement.preimage).unwrap_or_default();
+        build_zap_receipt(
+            &zap.zap_request,
+            &zap.comment,
+            &settlement.payment_request,
+            &preimage,
+            settlement.settle_date,
+        )
+    };
+
+    match receipt {
+        Ok((relays, message)) => {
+            if publish(relays, message).await == 0 {
+                warn!(target: "server::zap_receipts", "No relay accepted the receipt for {}, retrying later", payment_hash);
+                return;
+            }
+        }
+        Err(e) => {
+            error!(target: "server::zap_receipts", "Dropping the receipt for {}: {}", payment_hash, e)
+        }
+    }
+
+    let mut state = zap_state();
+    state.pending.remove(&payment_hash);
+    state.save();
+}